@@ -0,0 +1,383 @@
+use std::time::{Duration, Instant};
+
+use prusti_contracts::*;
+
+use crate::messaging::Message;
+use crate::receiver::state::inbound::Inbound;
+use crate::receiver::state::registry::Registry;
+use crate::receiver::state::{self as receiver, Capacity};
+use crate::receiver::ReceiverError;
+use crate::sender::state::error::Result as SenderResult;
+use crate::sender::state::{self as sender, Polled, Ready};
+use crate::sender::{Receipt, RetryPolicy, RttEstimator, SenderError};
+use crate::types::socket::Socket;
+use crate::types::transport::{Listener, Transport};
+use crate::types::MyResult::{Error, Value};
+use crate::types::next_seq;
+
+type MyResult<T> = crate::types::MyResult<T, DuplexError>;
+
+/// Messages received in order that recv has not handed out yet, the window advertised to the peer
+pub const INBOX_CAPACITY: usize = 64;
+/// How long an owed ack waits for outgoing data to ride on before it is sent alone
+pub const ACK_DELAY: Duration = Duration::from_millis(5);
+// Longest single read while recv waits without an owed ack
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub enum DuplexError {
+  SocketError,
+  HandshakeError,
+  Timeout,
+  NoResponse,
+}
+
+/// Both ends of a perfect link over one transport. Each direction has its own sequence space,
+/// messages are sent stop-and-wait and received in order exactly once.
+/// Every data frame carries the cumulative ack of the opposite direction, so request and reply
+/// traffic needs no separate ack frames.
+/// The sending half is the sender's Ready/Pending typestate, which owns the transport,
+/// the receiving half an Inbound that is handed the peer's frames. A failed send tears the
+/// link down, every later call fails with SocketError
+pub struct Duplex<T: Transport = Socket> {
+  // None once the link was torn down
+  out: Option<Ready<T>>,
+  inbound: Inbound,
+  policy: RetryPolicy,
+  rtt: RttEstimator,
+  // Seq of the next outgoing message
+  seq: u64,
+  standalone_acks: usize,
+}
+
+impl<T: Transport> Duplex<T> {
+  /// Open a duplex link to a peer waiting in accept. Our initial sequence number is announced
+  /// like a sender does, then the peer's is acknowledged like a receiver does.
+  /// Both announcements are retransmitted as the retry policy allows
+  pub fn connect(remote_addr: String, policy: RetryPolicy) -> MyResult<Duplex<T>> {
+    let attempts = policy.max_attempts.unwrap_or(usize::MAX);
+    let handshake = match sender::connect::<T>(remote_addr) {
+      SenderResult::Value(connect) => connect.handshake(policy.timeout, attempts),
+      SenderResult::Error(e) => SenderResult::Error(e),
+    };
+    let mut ready = match handshake {
+      SenderResult::Value(ready) => ready,
+      SenderResult::Error(e) => return Error(DuplexError::from_sender(e)),
+    };
+    let mut attempts = 0;
+    let peer_isn = loop {
+      if policy.gives_up(attempts) {
+        return Error(DuplexError::Timeout);
+      }
+      attempts += 1;
+      let deadline = Instant::now() + policy.timeout;
+      let announced = loop {
+        match ready.poll(deadline.saturating_duration_since(Instant::now())) {
+          SenderResult::Value(Some(Message::Connect {id})) => break Some(id),
+          // Repeated acks of our own announcement
+          SenderResult::Value(Some(_)) => {},
+          SenderResult::Value(None) => break None,
+          SenderResult::Error(e) => return Error(DuplexError::from_sender(e)),
+        }
+      };
+      if let Some(id) = announced {
+        break id;
+      }
+    };
+    let inbound = Inbound::new(peer_isn, Registry::new(next_seq(peer_isn)), Capacity::new(INBOX_CAPACITY), ACK_DELAY);
+    if let SenderResult::Error(e) = ready.send_ack(peer_isn, inbound.window()) {
+      return Error(DuplexError::from_sender(e));
+    }
+    Value(Duplex::new(ready, inbound, policy))
+  }
+
+  /// Accept the next peer: its initial sequence number is acknowledged like a receiver does,
+  /// then ours is announced like a sender does
+  pub fn accept<L: Listener<Transport = T>>(listener: &L, policy: RetryPolicy) -> MyResult<Duplex<T>> {
+    let socket = match listener.accept() {
+      Value(socket) => socket,
+      Error(_) => return Error(DuplexError::SocketError),
+    };
+    let (socket, inbound) = match receiver::accept_on(socket, Capacity::new(INBOX_CAPACITY)) {
+      receiver::error::Result::Value(listening) => listening.into_inbound(ACK_DELAY),
+      receiver::error::Result::Error(e) => return Error(DuplexError::from_receiver(e)),
+    };
+    let attempts = policy.max_attempts.unwrap_or(usize::MAX);
+    // Anything but the ack of our announcement, data included, is retransmitted by the peer
+    // once we are established
+    let connect = sender::announce_on(socket).answering(inbound.isn(), inbound.window());
+    match connect.handshake(policy.timeout, attempts) {
+      SenderResult::Value(ready) => Value(Duplex::new(ready, inbound, policy)),
+      SenderResult::Error(e) => Error(DuplexError::from_sender(e)),
+    }
+  }
+
+  fn new(out: Ready<T>, inbound: Inbound, policy: RetryPolicy) -> Self {
+    let rtt = RttEstimator::new(policy.timeout);
+    Duplex {seq: out.seq(), out: Some(out), inbound, policy, rtt, standalone_acks: 0}
+  }
+
+  /// Seq the next outgoing message will be sent with
+  #[pure]
+  pub fn seq(&self) -> u64 {
+    self.seq
+  }
+
+  /// Free inbox slots, advertised with every ack
+  #[pure]
+  #[trusted]
+  pub fn window(&self) -> u32 {
+    self.inbound.window()
+  }
+
+  /// Acks sent in frames of their own, every other ack rode on outgoing data
+  #[pure]
+  pub fn standalone_acks(&self) -> usize {
+    self.standalone_acks
+  }
+
+  pub fn rtt(&self) -> &RttEstimator {
+    &self.rtt
+  }
+
+  /// Send data and retransmit it until the peer acknowledges it, the frame also acknowledges
+  /// everything received so far. Messages the peer sends meanwhile are kept for recv
+  #[ensures(result.is_ok() ==> self.seq() == next_seq(old(self.seq())))]
+  #[ensures(!result.is_ok() ==> self.seq() == old(self.seq()))]
+  pub fn send(&mut self, data: Vec<u8>) -> MyResult<Receipt> {
+    let ready = match self.out.take() {
+      Some(ready) => ready,
+      None => return Error(DuplexError::SocketError),
+    };
+    let seq = ready.seq();
+    let (ack, window) = self.inbound.take_ack();
+    let mut pending = match ready.send_acking(data, ack, window) {
+      SenderResult::Value(pending) => pending,
+      SenderResult::Error(e) => return Error(DuplexError::from_sender(e)),
+    };
+    let mut attempts = 1;
+    let mut sent = Instant::now();
+    loop {
+      let timeout = (sent + self.rtt.rto()).saturating_duration_since(Instant::now());
+      pending = match pending.poll(timeout) {
+        SenderResult::Value(Polled::Delivered(ready, msg)) => {
+          if attempts == 1 {
+            self.rtt.sample(sent.elapsed());
+          } else {
+            self.rtt.reset_backoff();
+          }
+          self.seq = ready.seq();
+          self.out = Some(ready);
+          if let Some(msg) = msg {
+            if let Error(e) = self.handle(msg) {
+              return Error(e);
+            }
+          }
+          return Value(Receipt { seq, attempts });
+        },
+        SenderResult::Value(Polled::Frame(mut pending, msg)) => {
+          if let Error(e) = self.handle(msg) {
+            return Error(e);
+          }
+          // Our frame already left, the peer is not kept waiting for its ack
+          if self.inbound.ack_due().is_some() {
+            let (ack, window) = self.inbound.take_ack();
+            if let SenderResult::Error(e) = pending.send_ack(ack, window) {
+              return Error(DuplexError::from_sender(e));
+            }
+            self.standalone_acks += 1;
+          }
+          pending
+        },
+        SenderResult::Value(Polled::Expired(mut pending)) => {
+          if self.policy.gives_up(attempts) {
+            return Error(DuplexError::Timeout);
+          }
+          self.rtt.backoff();
+          attempts += 1;
+          let (ack, window) = self.inbound.take_ack();
+          if let SenderResult::Error(e) = pending.resend_acking(ack, window) {
+            return Error(DuplexError::from_sender(e));
+          }
+          sent = Instant::now();
+          pending
+        },
+        SenderResult::Error(e) => return Error(DuplexError::from_sender(e)),
+      };
+    }
+  }
+
+  /// Next message from the peer, in order. The ack is held back for up to ACK_DELAY
+  /// in case a reply can carry it
+  pub fn recv(&mut self) -> MyResult<(u64, Vec<u8>)> {
+    loop {
+      match self.recv_timeout(IDLE_WAIT) {
+        Value(Some(msg)) => return Value(msg),
+        Value(None) => {},
+        Error(e) => return Error(e),
+      }
+    }
+  }
+
+  /// Like recv, None once timeout has passed without a message
+  pub fn recv_timeout(&mut self, timeout: Duration) -> MyResult<Option<(u64, Vec<u8>)>> {
+    let end = Instant::now() + timeout;
+    loop {
+      if let Some(msg) = self.inbound.pop() {
+        return Value(Some(msg));
+      }
+      let deadline = match self.inbound.ack_due() {
+        Some(due) => due.min(end),
+        None => end,
+      };
+      let polled = match self.out.as_mut() {
+        Some(ready) => ready.poll(deadline.saturating_duration_since(Instant::now())),
+        None => return Error(DuplexError::SocketError),
+      };
+      match polled {
+        SenderResult::Value(Some(msg)) => {
+          if let Error(e) = self.handle(msg) {
+            return Error(e);
+          }
+        },
+        SenderResult::Value(None) => {},
+        SenderResult::Error(e) => return Error(DuplexError::from_sender(e)),
+      }
+      if let Error(e) = self.flush_due(Instant::now()) {
+        return Error(e);
+      }
+      if self.inbound.is_empty() && Instant::now() >= end {
+        return Value(None);
+      }
+    }
+  }
+
+  /// Send the owed ack now, e.g. before going quiet for a while
+  pub fn flush(&mut self) -> MyResult<()> {
+    self.flush_due(Instant::now() + ACK_DELAY)
+  }
+
+  /// Send the owed ack alone if it is due by then
+  fn flush_due(&mut self, by: Instant) -> MyResult<()> {
+    match self.inbound.ack_due() {
+      Some(due) if due <= by => {
+        let ready = match self.out.as_mut() {
+          Some(ready) => ready,
+          None => return Error(DuplexError::SocketError),
+        };
+        let (ack, window) = self.inbound.take_ack();
+        if let SenderResult::Error(e) = ready.send_ack(ack, window) {
+          return Error(DuplexError::from_sender(e));
+        }
+        self.standalone_acks += 1;
+        Value(())
+      },
+      _ => Value(()),
+    }
+  }
+
+  /// Hand a frame of the peer to the receiving half
+  fn handle(&mut self, msg: Message) -> MyResult<()> {
+    match self.inbound.handle(msg) {
+      receiver::error::Result::Value(_) => Value(()),
+      receiver::error::Result::Error(e) => Error(DuplexError::from_receiver(e)),
+    }
+  }
+}
+
+impl DuplexError {
+  fn from_sender(e: SenderError) -> Self {
+    match e {
+      SenderError::Timeout => DuplexError::Timeout,
+      SenderError::NoResponse => DuplexError::NoResponse,
+      SenderError::Refused => DuplexError::HandshakeError,
+      _ => DuplexError::SocketError,
+    }
+  }
+
+  fn from_receiver(e: ReceiverError) -> Self {
+    match e {
+      ReceiverError::HandshakeError => DuplexError::HandshakeError,
+      ReceiverError::RecvError => DuplexError::NoResponse,
+      _ => DuplexError::SocketError,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{thread, time::Duration};
+
+  use crate::messaging::Message;
+  use crate::sender::RetryPolicy;
+  use crate::types::loopback::{Loopback, LoopbackListener};
+  use crate::types::lossy::{simulate, LossConfig, Lossy, LossyListener};
+  use crate::types::next_seq;
+  use crate::types::transport::{Listener, Transport};
+  use crate::types::MyResult::Value;
+
+  use super::{Duplex, INBOX_CAPACITY};
+
+  #[test]
+  fn test_request_reply_piggybacks_acks() {
+    let addr = "duplex-piggyback";
+    let listener = LoopbackListener::bind(addr.to_string()).unwrap();
+    // The peer is a bare transport, so every frame the client sends is checked on the wire
+    let peer = thread::spawn(move || {
+      let mut s = listener.accept().unwrap();
+      let client_isn = s.recv_frame().unwrap().id();
+      s.send_frame(Message::Ack {id: client_isn, window: INBOX_CAPACITY as u32}).unwrap();
+      let isn = 1000;
+      s.send_frame(Message::Connect {id: isn}).unwrap();
+      assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: isn, window: INBOX_CAPACITY as u32});
+      let mut acked = isn;
+      for i in 0..10u8 {
+        let seq = next_seq(client_isn).wrapping_add(i as u64);
+        // Every request acknowledges the previous reply
+        assert_eq!(s.recv_frame().unwrap(), Message::DataAck {id: seq, data: vec![i], ack: acked, window: INBOX_CAPACITY as u32});
+        acked = isn + 1 + i as u64;
+        s.send_frame(Message::DataAck {id: acked, data: vec![i * 2], ack: seq, window: INBOX_CAPACITY as u32}).unwrap();
+      }
+      // Only the ack of the last reply has no request to ride on
+      assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: acked, window: INBOX_CAPACITY as u32});
+    });
+    let mut client: Duplex<Loopback> = Duplex::connect(addr.to_string(), RetryPolicy::forever(Duration::from_secs(1))).unwrap();
+    for i in 0..10u8 {
+      client.send(vec![i]).unwrap();
+      assert_eq!(client.recv().unwrap().1, vec![i * 2]);
+    }
+    client.flush().unwrap();
+    assert_eq!(client.standalone_acks(), 1);
+    peer.join().unwrap();
+  }
+
+  #[test]
+  fn test_duplex_over_fair_loss() {
+    let addr = "duplex-lossy";
+    simulate(addr, LossConfig { seed: 5, drop: 0.2, duplicate: 0.1, reorder: 0.1, delay: 0.1, max_delay: 3 }).unwrap();
+    let listener = LossyListener::<LoopbackListener>::bind(addr.to_string()).unwrap();
+    let policy = RetryPolicy::forever(Duration::from_millis(20));
+    let server_policy = policy.clone();
+    let server = thread::spawn(move || {
+      let mut server: Duplex<Lossy<Loopback>> = Duplex::accept(&listener, server_policy).unwrap();
+      let mut received = Vec::new();
+      for i in 0..20u8 {
+        received.push(server.recv().unwrap().1[0]);
+        server.send(vec![i]).unwrap();
+      }
+      // Keep acknowledging until the client hangs up, its last ack may need a few tries
+      while let Value((_, data)) = server.recv() {
+        received.push(data[0]);
+      }
+      received
+    });
+    let mut client: Duplex<Lossy<Loopback>> = Duplex::connect(addr.to_string(), policy).unwrap();
+    for i in 0..20u8 {
+      client.send(vec![i]).unwrap();
+      assert_eq!(client.recv().unwrap().1, vec![i]);
+    }
+    client.send(vec![20]).unwrap();
+    drop(client);
+    assert_eq!(server.join().unwrap(), (0..21u8).collect::<Vec<u8>>());
+  }
+}
@@ -1,5 +1,8 @@
 pub mod sender;
 pub mod receiver;
+pub mod duplex;
+pub mod mux;
+pub mod rpc;
 mod messaging;
 mod external;
 mod types;
@@ -13,8 +16,6 @@ use prusti_contracts::*;
 fn connect<A>(addr: A) -> io::Result<TcpStream>
 where A: ToSocketAddrs;
 
-pub const DATA_SIZE: usize = 1;
-
 #[derive(Clone)]
 pub struct Link {
     pub src: String,
@@ -1,54 +1,315 @@
 use super::*;
 
-#[derive(Clone)]
+/// Size of the length prefix that precedes every frame on the wire
+pub const HEADER_SIZE: usize = 4;
+/// Upper bound on the body of a single frame, anything larger is rejected as malformed
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+const DATA_CODE: u8 = 0x0;
+const ACK_CODE: u8 = 0x1;
+const CONNECT_CODE: u8 = 0x2;
+const SACK_CODE: u8 = 0x3;
+const PROBE_CODE: u8 = 0x4;
+const DATA_ACK_CODE: u8 = 0x5;
+const RESUME_CODE: u8 = 0x6;
+const REFUSE_CODE: u8 = 0x7;
+// Set in the code of a frame whose header carries a channel id
+const CHANNEL_FLAG: u8 = 0x80;
+const CHANNEL_SIZE: usize = 2;
+const ID_SIZE: usize = 8;
+const WINDOW_SIZE: usize = 4;
+
+/// Frame layout:
+/// [len: u32 big endian][code: u8][id: u64 big endian][payload...]
+/// where len counts every byte after the length prefix.
+/// Frames of a logical channel set the high bit of the code and carry the channel after it:
+/// [len: u32 big endian][code | 0x80: u8][channel: u16 big endian][id: u64 big endian][payload...]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Message {
-    Data {id: u8, data: u8},
-    Ack {id: u8},
+    Data {id: u64, data: Vec<u8>},
+    /// Cumulative ack, window is the number of free slots in the receiver's buffer
+    Ack {id: u64, window: u32},
+    /// Opens a link announcing the sender's initial sequence number,
+    /// the receiver confirms it with an Ack carrying the same id
+    Connect {id: u64},
+    /// Selective ack of a single seq buffered by the receiver past a gap,
+    /// unlike Ack it says nothing about the seqs before it
+    Sack {id: u64},
+    /// Sent while the receiver's window is closed to get a fresh Ack with its window,
+    /// id is the seq the sender would send next
+    Probe {id: u64},
+    /// Data of a duplex link carrying the cumulative ack and window of the opposite direction
+    DataAck {id: u64, data: Vec<u8>, ack: u64, window: u32},
+    /// Reopens the link whose Connect announced id over a new connection, the sender goes on at seq
+    /// and the seqs before it that were not delivered are given up. The receiver confirms with
+    /// an Ack carrying id, or answers Refuse if it no longer knows the link
+    Resume {id: u64, seq: u64},
+    /// The receiver will not serve the link announced by id, it forgot the link or cannot read
+    /// what it delivered. A sender resuming the link starts a fresh one instead
+    Refuse {id: u64},
+    /// Any other message on a logical channel, every channel has its own sequence space
+    Channel {channel: u16, msg: Box<Message>},
 }
 
 impl Message {
 
+  /// msg tagged with channel, replacing the channel it was on
+  pub fn on_channel(channel: u16, msg: Message) -> Message {
+    match msg {
+      Message::Channel {msg, ..} => Message::Channel {channel, msg},
+      msg => Message::Channel {channel, msg: Box::new(msg)},
+    }
+  }
+
   pub fn marshall(self) -> Vec<u8> {
+    let body = self.body();
+    let mut buf = Vec::with_capacity(HEADER_SIZE + body.len());
+    buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&body);
+    buf
+  }
+
+  fn body(self) -> Vec<u8> {
+    let mut body = Vec::new();
     match self {
       Message::Data {id, data} => {
-        let mut buf = Vec::new();
-        buf.push(0x0);
-        buf.push(id);
-        buf.push(data);
-        buf
-      },
-      Message::Ack {id} => {
-        let mut buf: Vec<u8> = Vec::new();
-        buf.push(0x1);
-        buf.push(id);
-        buf
+        body.push(DATA_CODE);
+        body.extend_from_slice(&id.to_be_bytes());
+        body.extend_from_slice(&data);
+      },
+      Message::Ack {id, window} => {
+        body.push(ACK_CODE);
+        body.extend_from_slice(&id.to_be_bytes());
+        body.extend_from_slice(&window.to_be_bytes());
+      },
+      Message::Connect {id} => {
+        body.push(CONNECT_CODE);
+        body.extend_from_slice(&id.to_be_bytes());
+      },
+      Message::Sack {id} => {
+        body.push(SACK_CODE);
+        body.extend_from_slice(&id.to_be_bytes());
+      },
+      Message::Probe {id} => {
+        body.push(PROBE_CODE);
+        body.extend_from_slice(&id.to_be_bytes());
+      },
+      Message::DataAck {id, data, ack, window} => {
+        body.push(DATA_ACK_CODE);
+        body.extend_from_slice(&id.to_be_bytes());
+        body.extend_from_slice(&ack.to_be_bytes());
+        body.extend_from_slice(&window.to_be_bytes());
+        body.extend_from_slice(&data);
+      },
+      Message::Resume {id, seq} => {
+        body.push(RESUME_CODE);
+        body.extend_from_slice(&id.to_be_bytes());
+        body.extend_from_slice(&seq.to_be_bytes());
+      },
+      Message::Refuse {id} => {
+        body.push(REFUSE_CODE);
+        body.extend_from_slice(&id.to_be_bytes());
+      },
+      Message::Channel {channel, msg} => {
+        body = msg.body();
+        body[0] |= CHANNEL_FLAG;
+        body.splice(1..1, channel.to_be_bytes());
       }
     }
+    body
   }
 
-
+  /// Decode a complete frame, length prefix included.
+  /// Returns None if the frame is incomplete or malformed
   pub fn unmarshall(buf: &[u8]) -> Option<Message> {
-    if buf.len() < 2 {
+    let len = Message::frame_len(buf)?;
+    if buf.len() < len {
       return None;
     }
-    let id = buf[1];
-    let code = buf[0];
+    let body = &buf[HEADER_SIZE..len];
+    if body.is_empty() || body[0] & CHANNEL_FLAG == 0 {
+      return Message::decode(body);
+    }
+    if body.len() < 1 + CHANNEL_SIZE {
+      return None;
+    }
+    let channel = u16::from_be_bytes([body[1], body[2]]);
+    let mut inner = vec![body[0] & !CHANNEL_FLAG];
+    inner.extend_from_slice(&body[1 + CHANNEL_SIZE..]);
+    let msg = Message::decode(&inner)?;
+    Some(Message::Channel {channel, msg: Box::new(msg)})
+  }
+
+  /// Decode the body of an untagged frame
+  fn decode(body: &[u8]) -> Option<Message> {
+    if body.len() < 1 + ID_SIZE {
+      return None;
+    }
+    let code = body[0];
+    let mut id_bytes = [0; ID_SIZE];
+    id_bytes.copy_from_slice(&body[1..1 + ID_SIZE]);
+    let id = u64::from_be_bytes(id_bytes);
     match code {
-      0x0 => {
-        if buf.len() < 3 {
+      DATA_CODE => Some(Message::Data {id, data: body[1 + ID_SIZE..].to_vec()}),
+      DATA_ACK_CODE => {
+        let header = 1 + ID_SIZE + ID_SIZE + WINDOW_SIZE;
+        if body.len() < header {
+          return None;
+        }
+        let mut ack_bytes = [0; ID_SIZE];
+        ack_bytes.copy_from_slice(&body[1 + ID_SIZE..1 + 2 * ID_SIZE]);
+        let mut window_bytes = [0; WINDOW_SIZE];
+        window_bytes.copy_from_slice(&body[1 + 2 * ID_SIZE..header]);
+        Some(Message::DataAck {
+          id,
+          data: body[header..].to_vec(),
+          ack: u64::from_be_bytes(ack_bytes),
+          window: u32::from_be_bytes(window_bytes),
+        })
+      },
+      ACK_CODE => {
+        if body.len() != 1 + ID_SIZE + WINDOW_SIZE {
+          return None;
+        }
+        let mut window_bytes = [0; WINDOW_SIZE];
+        window_bytes.copy_from_slice(&body[1 + ID_SIZE..]);
+        Some(Message::Ack {id, window: u32::from_be_bytes(window_bytes)})
+      },
+      RESUME_CODE => {
+        if body.len() != 1 + 2 * ID_SIZE {
+          return None;
+        }
+        let mut seq_bytes = [0; ID_SIZE];
+        seq_bytes.copy_from_slice(&body[1 + ID_SIZE..]);
+        Some(Message::Resume {id, seq: u64::from_be_bytes(seq_bytes)})
+      },
+      CONNECT_CODE | SACK_CODE | PROBE_CODE | REFUSE_CODE => {
+        if body.len() != 1 + ID_SIZE {
           return None;
         }
-        Some(Message::Data {id, data: buf[2]})
+        match code {
+          CONNECT_CODE => Some(Message::Connect {id}),
+          SACK_CODE => Some(Message::Sack {id}),
+          PROBE_CODE => Some(Message::Probe {id}),
+          _ => Some(Message::Refuse {id})
+        }
       },
-      0x1 => Some(Message::Ack {id}),
       _ => None
     }
   }
 
-  fn id(&self) -> u8 {
+  /// Total length of the frame starting at buf, length prefix included.
+  /// Returns None if the prefix has not been fully read or announces an oversized frame
+  pub fn frame_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < HEADER_SIZE {
+      return None;
+    }
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if len > MAX_FRAME_SIZE {
+      return None;
+    }
+    Some(HEADER_SIZE + len)
+  }
+
+  pub fn id(&self) -> u64 {
     match self {
       Message::Data {id, ..} => *id,
-      Message::Ack {id} => *id
+      Message::Ack {id, ..} => *id,
+      Message::Connect {id} => *id,
+      Message::Sack {id} => *id,
+      Message::Probe {id} => *id,
+      Message::DataAck {id, ..} => *id,
+      Message::Resume {id, ..} => *id,
+      Message::Refuse {id} => *id,
+      Message::Channel {msg, ..} => msg.id()
     }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_data_roundtrip() {
+    let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+    let msg = Message::Data {id: 7, data};
+    let buf = msg.clone().marshall();
+    assert_eq!(Message::frame_len(&buf), Some(buf.len()));
+    assert_eq!(Message::unmarshall(&buf), Some(msg));
+  }
+
+  #[test]
+  fn test_ack_roundtrip() {
+    let msg = Message::Ack {id: u64::MAX - 3, window: 7};
+    let buf = msg.clone().marshall();
+    assert_eq!(buf.len(), HEADER_SIZE + 1 + ID_SIZE + WINDOW_SIZE);
+    assert_eq!(Message::unmarshall(&buf), Some(msg));
+  }
+
+  #[test]
+  fn test_connect_roundtrip() {
+    let msg = Message::Connect {id: 42};
+    let buf = msg.clone().marshall();
+    assert_eq!(Message::unmarshall(&buf), Some(msg));
+  }
+
+  #[test]
+  fn test_sack_roundtrip() {
+    let msg = Message::Sack {id: 9};
+    let buf = msg.clone().marshall();
+    assert_eq!(buf.len(), HEADER_SIZE + 1 + ID_SIZE);
+    assert_eq!(Message::unmarshall(&buf), Some(msg));
+  }
+
+  #[test]
+  fn test_probe_roundtrip() {
+    let msg = Message::Probe {id: 5};
+    let buf = msg.clone().marshall();
+    assert_eq!(Message::unmarshall(&buf), Some(msg));
+  }
+
+  #[test]
+  fn test_data_ack_roundtrip() {
+    let msg = Message::DataAck {id: 3, data: vec![1, 2], ack: u64::MAX, window: 9};
+    let buf = msg.clone().marshall();
+    assert_eq!(buf.len(), HEADER_SIZE + 1 + 2 * ID_SIZE + WINDOW_SIZE + 2);
+    assert_eq!(Message::unmarshall(&buf), Some(msg));
+  }
+
+  #[test]
+  fn test_resume_roundtrip() {
+    let msg = Message::Resume {id: 11, seq: u64::MAX};
+    let buf = msg.clone().marshall();
+    assert_eq!(buf.len(), HEADER_SIZE + 1 + 2 * ID_SIZE);
+    assert_eq!(Message::unmarshall(&buf), Some(msg));
+    assert_eq!(Message::unmarshall(&buf[..buf.len() - 1]), None);
+  }
+
+  #[test]
+  fn test_refuse_roundtrip() {
+    let msg = Message::Refuse {id: 11};
+    let buf = msg.clone().marshall();
+    assert_eq!(buf.len(), HEADER_SIZE + 1 + ID_SIZE);
+    assert_eq!(Message::unmarshall(&buf), Some(msg));
+  }
+
+  #[test]
+  fn test_channel_roundtrip() {
+    let msg = Message::on_channel(0x0102, Message::Ack {id: 4, window: 2});
+    let buf = msg.clone().marshall();
+    assert_eq!(buf.len(), HEADER_SIZE + 1 + CHANNEL_SIZE + ID_SIZE + WINDOW_SIZE);
+    assert_eq!(buf[HEADER_SIZE], ACK_CODE | CHANNEL_FLAG);
+    assert_eq!(Message::unmarshall(&buf), Some(msg.clone()));
+    assert_eq!(Message::on_channel(3, msg).id(), 4);
+    let data = Message::on_channel(0, Message::Data {id: 1, data: vec![5; 3]});
+    assert_eq!(Message::unmarshall(&data.clone().marshall()), Some(data));
+  }
+
+  #[test]
+  fn test_incomplete_frame() {
+    let buf = Message::Data {id: 1, data: vec![1, 2, 3]}.marshall();
+    assert_eq!(Message::unmarshall(&buf[..buf.len() - 1]), None);
+    assert_eq!(Message::frame_len(&buf[..HEADER_SIZE - 1]), None);
+  }
+}
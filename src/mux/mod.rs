@@ -0,0 +1,79 @@
+use crate::types::socket::SocketError;
+
+mod receiver;
+mod sender;
+
+pub use self::receiver::MuxReceiver;
+pub use self::sender::MuxSender;
+
+/// Priority of control traffic, scheduled and delivered before every other channel
+pub const CONTROL: u8 = 0;
+/// Least urgent priority, the receiver's default for channels it was not told about
+pub const BULK: u8 = u8::MAX;
+/// Messages the receiver holds per channel, in order or past a gap, advertised as its window
+pub const INBOX_CAPACITY: usize = 64;
+
+#[derive(Clone, Debug)]
+pub enum MuxError {
+  SocketError,
+  HandshakeError,
+  Timeout,
+  NoResponse,
+  UnknownChannel,
+}
+
+impl MuxError {
+  fn from_socket(e: SocketError) -> Self {
+    match e {
+      SocketError::SetTimeoutFailed => MuxError::SocketError,
+      _ => MuxError::NoResponse,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{thread, time::Duration};
+
+  use crate::sender::RetryPolicy;
+  use crate::types::loopback::{Loopback, LoopbackListener};
+  use crate::types::lossy::{simulate, LossConfig, Lossy, LossyListener};
+  use crate::types::transport::Listener;
+
+  use super::{MuxReceiver, MuxSender, BULK, CONTROL};
+
+  #[test]
+  fn test_channels_over_fair_loss() {
+    let addr = "mux-lossy";
+    simulate(addr, LossConfig { seed: 9, drop: 0.2, duplicate: 0.1, reorder: 0.2, delay: 0.1, max_delay: 3 }).unwrap();
+    let listener = LossyListener::<LoopbackListener>::bind(addr.to_string()).unwrap();
+    let receiver = thread::spawn(move || {
+      let mut receiver: MuxReceiver<Lossy<Loopback>> = MuxReceiver::accept(&listener).unwrap();
+      receiver.set_priority(0, CONTROL);
+      let mut received = vec![Vec::new(), Vec::new()];
+      for _ in 0..45 {
+        let (channel, _, data) = receiver.recv().unwrap();
+        received[channel as usize].push(data[0]);
+      }
+      // Keep acknowledging until the sender hangs up
+      let _ = receiver.recv();
+      received
+    });
+    let mut sender: MuxSender<Lossy<Loopback>> = MuxSender::connect(addr.to_string(), RetryPolicy::forever(Duration::from_millis(20))).unwrap();
+    sender.open(0, CONTROL, 2);
+    sender.open(1, BULK, 8);
+    for i in 0..40 {
+      sender.enqueue(1, vec![i]).unwrap();
+      if i % 8 == 0 {
+        sender.enqueue(0, vec![i / 8]).unwrap();
+      }
+    }
+    let receipts = sender.flush().unwrap();
+    assert_eq!(receipts.len(), 45);
+    assert_eq!(sender.npending(), 0);
+    drop(sender);
+    let received = receiver.join().unwrap();
+    assert_eq!(received[0], (0..5).collect::<Vec<u8>>());
+    assert_eq!(received[1], (0..40).collect::<Vec<u8>>());
+  }
+}
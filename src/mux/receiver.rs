@@ -0,0 +1,234 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use prusti_contracts::*;
+
+use crate::messaging::Message;
+use crate::receiver::state::error::Result as ReceiverResult;
+use crate::receiver::state::registry::Registry;
+use crate::receiver::state::{self as receiver, Capacity};
+use crate::receiver::{ReceiverError, REORDER_CAPACITY};
+use crate::types::next_seq;
+use crate::types::socket::Socket;
+use crate::types::transport::{recv_until, Listener, Transport};
+use crate::types::MyResult::{Error, Value};
+use super::{MuxError, BULK, INBOX_CAPACITY};
+
+type MyResult<T> = crate::types::MyResult<T, MuxError>;
+
+// Longest single read while recv waits for a message
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// Receiving half of one logical channel
+struct Incoming {
+  priority: u8,
+  // Seqs taken into the inbox
+  registry: Registry,
+  // Received past a gap
+  buffer: Vec<(u64, Vec<u8>)>,
+  // In order, not handed to the application yet
+  inbox: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl Incoming {
+  fn new(first: u64) -> Self {
+    Incoming { priority: BULK, registry: Registry::new(first), buffer: Vec::new(), inbox: VecDeque::new() }
+  }
+
+  #[pure]
+  #[trusted]
+  fn window(&self) -> u32 {
+    INBOX_CAPACITY.saturating_sub(self.inbox.len() + self.buffer.len()) as u32
+  }
+
+  fn ack(&self) -> Message {
+    Message::Ack {id: self.registry.acked(), window: self.window()}
+  }
+
+  /// Take in a data frame and return the ack to answer it with, None drops it unacknowledged
+  fn accept(&mut self, seq: u64, data: Vec<u8>) -> Option<Message> {
+    // The ack was lost and the sender retransmitted
+    if self.registry.contains(seq) {
+      return Some(self.ack());
+    }
+    if self.buffer.iter().any(|(buffered, _)| *buffered == seq) {
+      return Some(Message::Sack {id: seq});
+    }
+    if self.window() == 0 || seq.wrapping_sub(self.registry.next()) >= REORDER_CAPACITY as u64 {
+      return None;
+    }
+    if seq != self.registry.next() {
+      self.buffer.push((seq, data));
+      return Some(Message::Sack {id: seq});
+    }
+    self.registry.insert(seq);
+    self.inbox.push_back((seq, data));
+    while let Some(i) = self.buffer.iter().position(|(buffered, _)| *buffered == self.registry.next()) {
+      let (seq, data) = self.buffer.swap_remove(i);
+      self.registry.insert(seq);
+      self.inbox.push_back((seq, data));
+    }
+    Some(self.ack())
+  }
+}
+
+/// Receiving end of the channels of one MuxSender. Every channel is ordered and deduplicated
+/// on its own, a gap in one channel never holds back the others, and recv hands out
+/// the most urgent channel's messages first
+pub struct MuxReceiver<T: Transport = Socket> {
+  socket: T,
+  isn: u64,
+  channels: BTreeMap<u16, Incoming>,
+  // Channel delivered from last, channels of equal priority take turns after it
+  last: Option<u16>,
+}
+
+impl<T: Transport> MuxReceiver<T> {
+  /// Accept the next sender and confirm its initial sequence number
+  pub fn accept<L: Listener<Transport = T>>(listener: &L) -> MyResult<MuxReceiver<T>> {
+    let socket = match listener.accept() {
+      Value(socket) => socket,
+      Error(_) => return Error(MuxError::SocketError),
+    };
+    match receiver::accept_on(socket, Capacity::new(INBOX_CAPACITY)) {
+      ReceiverResult::Value(listening) => {
+        let (socket, isn) = listening.into_transport();
+        Value(MuxReceiver { socket, isn, channels: BTreeMap::new(), last: None })
+      },
+      ReceiverResult::Error(ReceiverError::HandshakeError) => Error(MuxError::HandshakeError),
+      ReceiverResult::Error(ReceiverError::RecvError) => Error(MuxError::NoResponse),
+      ReceiverResult::Error(_) => Error(MuxError::SocketError),
+    }
+  }
+
+  /// Deliver channel's messages before those of less urgent channels, lower goes first.
+  /// Channels default to BULK
+  pub fn set_priority(&mut self, channel: u16, priority: u8) {
+    self.incoming(channel).priority = priority;
+  }
+
+  /// Messages received in order and not handed out yet, on every channel
+  #[pure]
+  #[trusted]
+  pub fn nready(&self) -> usize {
+    self.channels.values().map(|incoming| incoming.inbox.len()).sum()
+  }
+
+  /// Next message as (channel, seq, data). Frames that already arrived are taken in first,
+  /// without waiting for more, so an urgent message is not stuck behind a backlog of bulk ones.
+  /// At most INBOX_CAPACITY of them, a busy link cannot hold back delivery forever
+  pub fn recv(&mut self) -> MyResult<(u16, u64, Vec<u8>)> {
+    loop {
+      if self.nready() > 0 {
+        for _ in 0..INBOX_CAPACITY {
+          // Acknowledged messages are handed out even after the sender hung up
+          let msg = match self.socket.try_recv_frame() {
+            Value(msg) => msg,
+            Error(_) => break,
+          };
+          if let Error(e) = self.handle(msg) {
+            return Error(e);
+          }
+        }
+        return self.pop();
+      }
+      match recv_until(&mut self.socket, Instant::now() + IDLE_WAIT) {
+        Value(Some(msg)) => {
+          if let Error(e) = self.handle(msg) {
+            return Error(e);
+          }
+        },
+        Value(None) => {},
+        Error(e) => return Error(MuxError::from_socket(e)),
+      }
+    }
+  }
+
+  fn incoming(&mut self, channel: u16) -> &mut Incoming {
+    let first = next_seq(self.isn);
+    self.channels.entry(channel).or_insert_with(|| Incoming::new(first))
+  }
+
+  fn handle(&mut self, msg: Message) -> MyResult<()> {
+    let reply = match msg {
+      Message::Channel {channel, msg} => {
+        let incoming = self.incoming(channel);
+        let reply = match *msg {
+          Message::Data {id, data} => incoming.accept(id, data),
+          // The sender waits for the window to reopen
+          Message::Probe {..} => Some(incoming.ack()),
+          _ => None,
+        };
+        reply.map(|reply| Message::on_channel(channel, reply))
+      },
+      // The ack of the handshake was lost
+      Message::Connect {id} if id == self.isn => Some(Message::Ack {id, window: INBOX_CAPACITY as u32}),
+      Message::Connect {..} => return Error(MuxError::HandshakeError),
+      _ => None,
+    };
+    match reply.map(|reply| self.socket.send_frame(reply)) {
+      Some(Error(_)) => Error(MuxError::SocketError),
+      _ => Value(()),
+    }
+  }
+
+  /// Hand out the front of the most urgent non-empty inbox
+  #[requires(self.nready() > 0)]
+  fn pop(&mut self) -> MyResult<(u16, u64, Vec<u8>)> {
+    let priority = self.channels.values().filter(|c| !c.inbox.is_empty()).map(|c| c.priority).min().unwrap();
+    let ready: Vec<u16> = self.channels.iter()
+      .filter(|(_, c)| !c.inbox.is_empty() && c.priority == priority)
+      .map(|(channel, _)| *channel)
+      .collect();
+    let channel = match self.last {
+      Some(last) => *ready.iter().find(|channel| **channel > last).unwrap_or(&ready[0]),
+      None => ready[0],
+    };
+    self.last = Some(channel);
+    let incoming = self.channels.get_mut(&channel).unwrap();
+    let closed = incoming.window() == 0;
+    let (seq, data) = incoming.inbox.pop_front().unwrap();
+    // Tell the sender its window reopened
+    if closed && self.socket.send_frame(Message::on_channel(channel, incoming.ack())).is_err() {
+      return Error(MuxError::SocketError);
+    }
+    Value((channel, seq, data))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::messaging::Message;
+  use crate::types::loopback::{Loopback, LoopbackListener};
+  use crate::types::transport::{Listener, Transport};
+
+  use super::super::CONTROL;
+  use super::MuxReceiver;
+
+  #[test]
+  fn test_channels_are_ordered_independently() {
+    let addr = "mux-receiver";
+    let listener = LoopbackListener::bind(addr.to_string()).unwrap();
+    let mut peer = Loopback::connect(addr.to_string()).unwrap();
+    let isn = 41;
+    peer.send_frame(Message::Connect {id: isn}).unwrap();
+    let mut receiver: MuxReceiver<Loopback> = MuxReceiver::accept(&listener).unwrap();
+    receiver.set_priority(0, CONTROL);
+    assert_eq!(peer.recv_frame().unwrap(), Message::Ack {id: isn, window: 64});
+    // Bulk loses its first message, control is not held back by the gap nor by the bulk backlog
+    peer.send_frame(Message::on_channel(1, Message::Data {id: 43, data: vec![2]})).unwrap();
+    peer.send_frame(Message::on_channel(1, Message::Data {id: 42, data: vec![1]})).unwrap();
+    peer.send_frame(Message::on_channel(0, Message::Data {id: 42, data: vec![0]})).unwrap();
+    assert_eq!(receiver.recv().unwrap(), (0, 42, vec![0]));
+    assert_eq!(receiver.recv().unwrap(), (1, 42, vec![1]));
+    assert_eq!(receiver.recv().unwrap(), (1, 43, vec![2]));
+    assert_eq!(peer.recv_frame().unwrap(), Message::on_channel(1, Message::Sack {id: 43}));
+    assert_eq!(peer.recv_frame().unwrap(), Message::on_channel(1, Message::Ack {id: 43, window: 62}));
+    assert_eq!(peer.recv_frame().unwrap(), Message::on_channel(0, Message::Ack {id: 42, window: 63}));
+    // A retransmission is acknowledged again but not delivered twice
+    peer.send_frame(Message::on_channel(0, Message::Data {id: 42, data: vec![0]})).unwrap();
+    peer.send_frame(Message::on_channel(0, Message::Data {id: 43, data: vec![3]})).unwrap();
+    assert_eq!(receiver.recv().unwrap(), (0, 43, vec![3]));
+    assert_eq!(peer.recv_frame().unwrap(), Message::on_channel(0, Message::Ack {id: 42, window: 64}));
+  }
+}
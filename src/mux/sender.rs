@@ -0,0 +1,329 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use prusti_contracts::*;
+
+use crate::messaging::Message;
+use crate::sender::state::error::Result as SenderResult;
+use crate::sender::state as sender;
+use crate::sender::{Receipt, RetryPolicy, RttEstimator, SenderError};
+use crate::types::socket::Socket;
+use crate::types::transport::{recv_until, Transport};
+use crate::types::MyResult::{Error, Value};
+use crate::types::{next_seq, seq_lt};
+use super::{MuxError, INBOX_CAPACITY};
+
+type MyResult<T> = crate::types::MyResult<T, MuxError>;
+
+/// A message sent but not slid out of its channel's window yet
+struct InFlight {
+  seq: u64,
+  data: Vec<u8>,
+  attempts: usize,
+  sent: Instant,
+  acked: bool,
+}
+
+/// Sending half of one logical channel
+struct Outgoing {
+  priority: u8,
+  capacity: usize,
+  // Seq of the next new message
+  seq: u64,
+  queue: VecDeque<Vec<u8>>,
+  // Ordered by seq, the front is always unacknowledged
+  outstanding: VecDeque<InFlight>,
+  rwnd: u32,
+}
+
+impl Outgoing {
+  fn can_send(&self) -> bool {
+    !self.queue.is_empty() && self.outstanding.len() < self.capacity.min(self.rwnd as usize)
+  }
+
+  /// Mark the messages covered by an ack, a cumulative one carries the window.
+  /// Returns the messages slid out of the window and the round trip of the one the ack names,
+  /// if it was acknowledged on its first transmission
+  fn ack(&mut self, id: u64, window: Option<u32>) -> (Vec<Receipt>, Option<Duration>) {
+    let base = self.outstanding.front().map_or(self.seq, |front| front.seq);
+    let mut rtt = None;
+    for in_flight in self.outstanding.iter_mut() {
+      if in_flight.seq == id && !in_flight.acked && in_flight.attempts == 1 {
+        rtt = Some(in_flight.sent.elapsed());
+      }
+      if in_flight.seq == id || (window.is_some() && seq_lt(in_flight.seq, id)) {
+        in_flight.acked = true;
+      }
+    }
+    match window {
+      Some(window) if !seq_lt(id, base.wrapping_sub(1)) => self.rwnd = window,
+      _ => {},
+    }
+    let mut receipts = Vec::new();
+    while self.outstanding.front().is_some_and(|front| front.acked) {
+      let front = self.outstanding.pop_front().unwrap();
+      receipts.push(Receipt { seq: front.seq, attempts: front.attempts });
+    }
+    (receipts, rtt)
+  }
+}
+
+/// Sending end of several independent ordered streams sharing one transport.
+/// Every channel has its own sequence space and window, so a lost message only holds back
+/// its own channel, and the most urgent channel with room in its window always sends first
+pub struct MuxSender<T: Transport = Socket> {
+  socket: T,
+  policy: RetryPolicy,
+  rtt: RttEstimator,
+  isn: u64,
+  channels: BTreeMap<u16, Outgoing>,
+  // Channel scheduled last, channels of equal priority take turns after it
+  last: Option<u16>,
+}
+
+impl<T: Transport> MuxSender<T> {
+  /// Connect to a MuxReceiver, the initial sequence number is shared by every channel
+  pub fn connect(remote_addr: String, policy: RetryPolicy) -> MyResult<MuxSender<T>> {
+    let connect = match sender::connect::<T>(remote_addr) {
+      SenderResult::Value(connect) => connect,
+      SenderResult::Error(_) => return Error(MuxError::SocketError),
+    };
+    let isn = connect.seq();
+    match connect.handshake(policy.timeout, policy.max_attempts.unwrap_or(usize::MAX)) {
+      SenderResult::Value(ready) => {
+        let rtt = RttEstimator::new(policy.timeout);
+        Value(MuxSender { socket: ready.into_transport(), policy, rtt, isn, channels: BTreeMap::new(), last: None })
+      },
+      SenderResult::Error(SenderError::Timeout) => Error(MuxError::Timeout),
+      SenderResult::Error(SenderError::NoResponse) => Error(MuxError::NoResponse),
+      SenderResult::Error(SenderError::Refused) => Error(MuxError::HandshakeError),
+      SenderResult::Error(_) => Error(MuxError::SocketError),
+    }
+  }
+
+  /// Open channel, or change the settings of an open one. Lower priorities are sent first,
+  /// capacity bounds the messages of the channel in flight
+  #[requires(capacity > 0)]
+  pub fn open(&mut self, channel: u16, priority: u8, capacity: usize) {
+    let first = next_seq(self.isn);
+    let outgoing = self.channels.entry(channel).or_insert_with(|| Outgoing {
+      priority,
+      capacity,
+      seq: first,
+      queue: VecDeque::new(),
+      outstanding: VecDeque::new(),
+      rwnd: INBOX_CAPACITY as u32,
+    });
+    outgoing.priority = priority;
+    outgoing.capacity = capacity;
+  }
+
+  /// Queue data on an open channel, it is sent by poll or flush
+  pub fn enqueue(&mut self, channel: u16, data: Vec<u8>) -> MyResult<()> {
+    match self.channels.get_mut(&channel) {
+      Some(outgoing) => {
+        outgoing.queue.push_back(data);
+        Value(())
+      },
+      None => Error(MuxError::UnknownChannel),
+    }
+  }
+
+  /// Messages queued or in flight on every channel
+  #[pure]
+  #[trusted]
+  pub fn npending(&self) -> usize {
+    self.channels.values().map(|outgoing| outgoing.queue.len() + outgoing.outstanding.len()).sum()
+  }
+
+  pub fn rtt(&self) -> &RttEstimator {
+    &self.rtt
+  }
+
+  /// Send what the windows allow in priority order, wait up to timeout for acks, then retransmit
+  /// the messages whose timer ran out. Returns the messages acknowledged meanwhile with their channel
+  pub fn poll(&mut self, timeout: Duration) -> MyResult<Vec<(u16, Receipt)>> {
+    if let Error(e) = self.schedule() {
+      return Error(e);
+    }
+    let acked = match self.wait_acks(timeout) {
+      Value(acked) => acked,
+      Error(e) => return Error(e),
+    };
+    match self.retransmit_expired() {
+      Value(_) => Value(acked),
+      Error(e) => Error(e),
+    }
+  }
+
+  /// Poll until every queued message is acknowledged
+  #[ensures(result.is_ok() ==> self.npending() == 0)]
+  pub fn flush(&mut self) -> MyResult<Vec<(u16, Receipt)>> {
+    let mut receipts = Vec::new();
+    while self.npending() > 0 {
+      match self.poll(self.rtt.rto()) {
+        Value(acked) => receipts.extend(acked),
+        Error(e) => return Error(e),
+      }
+    }
+    Value(receipts)
+  }
+
+  /// The most urgent channel with a queued message and room in its window,
+  /// the one after the last scheduled channel among equally urgent ones
+  fn next_channel(&self) -> Option<u16> {
+    let priority = self.channels.values().filter(|c| c.can_send()).map(|c| c.priority).min()?;
+    let eligible: Vec<u16> = self.channels.iter()
+      .filter(|(_, c)| c.can_send() && c.priority == priority)
+      .map(|(channel, _)| *channel)
+      .collect();
+    match self.last {
+      Some(last) => eligible.iter().find(|channel| **channel > last).or(eligible.first()).copied(),
+      None => eligible.first().copied(),
+    }
+  }
+
+  fn schedule(&mut self) -> MyResult<()> {
+    while let Some(channel) = self.next_channel() {
+      let outgoing = self.channels.get_mut(&channel).unwrap();
+      let data = outgoing.queue.pop_front().unwrap();
+      let seq = outgoing.seq;
+      let frame = Message::on_channel(channel, Message::Data {id: seq, data: data.clone()});
+      if self.socket.send_frame(frame).is_err() {
+        return Error(MuxError::SocketError);
+      }
+      outgoing.outstanding.push_back(InFlight {seq, data, attempts: 1, sent: Instant::now(), acked: false});
+      outgoing.seq = next_seq(seq);
+      self.last = Some(channel);
+    }
+    // A channel whose window closed asks for a fresh one
+    for (channel, outgoing) in self.channels.iter() {
+      if outgoing.rwnd == 0 && outgoing.outstanding.is_empty() && !outgoing.queue.is_empty() {
+        let probe = Message::on_channel(*channel, Message::Probe {id: outgoing.seq});
+        if self.socket.send_frame(probe).is_err() {
+          return Error(MuxError::SocketError);
+        }
+      }
+    }
+    Value(())
+  }
+
+  /// Earliest moment a retransmission timer runs out, None when nothing is outstanding
+  fn deadline(&self) -> Option<Instant> {
+    let rto = self.rtt.rto();
+    self.channels.values()
+      .flat_map(|outgoing| outgoing.outstanding.iter())
+      .filter(|in_flight| !in_flight.acked)
+      .map(|in_flight| in_flight.sent + rto)
+      .min()
+  }
+
+  /// Returns as soon as a window slides or reopens, so the scheduler can fill it again
+  fn wait_acks(&mut self, timeout: Duration) -> MyResult<Vec<(u16, Receipt)>> {
+    let end = Instant::now() + timeout;
+    let mut acked = Vec::new();
+    loop {
+      let deadline = self.deadline().map_or(end, |deadline| deadline.min(end));
+      let (channel, id, window) = match recv_until(&mut self.socket, deadline) {
+        Value(Some(Message::Channel {channel, msg})) => match *msg {
+          Message::Ack {id, window} => (channel, id, Some(window)),
+          Message::Sack {id} => (channel, id, None),
+          _ => continue,
+        },
+        Value(Some(_)) => continue,
+        Value(None) => return Value(acked),
+        Error(e) => return Error(MuxError::from_socket(e)),
+      };
+      let outgoing = match self.channels.get_mut(&channel) {
+        Some(outgoing) => outgoing,
+        None => continue,
+      };
+      let rwnd = outgoing.rwnd;
+      let (receipts, rtt) = outgoing.ack(id, window);
+      let reopened = rwnd == 0 && outgoing.rwnd > 0;
+      if let Some(rtt) = rtt {
+        self.rtt.sample(rtt);
+      } else if receipts.iter().any(|receipt| receipt.attempts > 1) {
+        self.rtt.reset_backoff();
+      }
+      acked.extend(receipts.into_iter().map(|receipt| (channel, receipt)));
+      if !acked.is_empty() || reopened {
+        return Value(acked);
+      }
+    }
+  }
+
+  fn retransmit_expired(&mut self) -> MyResult<()> {
+    let rto = self.rtt.rto();
+    let mut expired = false;
+    for (channel, outgoing) in self.channels.iter_mut() {
+      for in_flight in outgoing.outstanding.iter_mut() {
+        if in_flight.acked || in_flight.sent.elapsed() < rto {
+          continue;
+        }
+        if self.policy.gives_up(in_flight.attempts) {
+          return Error(MuxError::Timeout);
+        }
+        let frame = Message::on_channel(*channel, Message::Data {id: in_flight.seq, data: in_flight.data.clone()});
+        if self.socket.send_frame(frame).is_err() {
+          return Error(MuxError::SocketError);
+        }
+        in_flight.attempts += 1;
+        in_flight.sent = Instant::now();
+        expired = true;
+      }
+    }
+    if expired {
+      self.rtt.backoff();
+    }
+    Value(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{thread, time::Duration};
+
+  use crate::messaging::Message;
+  use crate::sender::RetryPolicy;
+  use crate::types::loopback::{Loopback, LoopbackListener};
+  use crate::types::transport::{Listener, Transport};
+
+  use super::super::{BULK, CONTROL};
+  use super::MuxSender;
+
+  #[test]
+  fn test_control_overtakes_bulk() {
+    let addr = "mux-priority";
+    let listener = LoopbackListener::bind(addr.to_string()).unwrap();
+    let connect = thread::spawn(move || {
+      MuxSender::<Loopback>::connect(addr.to_string(), RetryPolicy::forever(Duration::from_secs(1))).unwrap()
+    });
+    let mut peer = listener.accept().unwrap();
+    let isn = peer.recv_frame().unwrap().id();
+    peer.send_frame(Message::Ack {id: isn, window: 8}).unwrap();
+    let mut sender = connect.join().unwrap();
+    let first = isn.wrapping_add(1);
+    sender.open(1, BULK, 2);
+    sender.open(0, CONTROL, 2);
+    for i in 0..4 {
+      sender.enqueue(1, vec![i]).unwrap();
+    }
+    sender.enqueue(0, vec![9]).unwrap();
+    assert!(sender.poll(Duration::from_millis(10)).unwrap().is_empty());
+    // Control was queued last but goes first, every channel counts from the same first seq
+    assert_eq!(peer.recv_frame().unwrap(), Message::on_channel(0, Message::Data {id: first, data: vec![9]}));
+    assert_eq!(peer.recv_frame().unwrap(), Message::on_channel(1, Message::Data {id: first, data: vec![0]}));
+    assert_eq!(peer.recv_frame().unwrap(), Message::on_channel(1, Message::Data {id: first.wrapping_add(1), data: vec![1]}));
+    // A full bulk window does not hold back control
+    sender.enqueue(0, vec![8]).unwrap();
+    peer.send_frame(Message::on_channel(1, Message::Ack {id: first, window: 8})).unwrap();
+    let acked = sender.poll(Duration::from_secs(1)).unwrap();
+    assert_eq!(acked.len(), 1);
+    assert_eq!(acked[0].0, 1);
+    assert_eq!(peer.recv_frame().unwrap(), Message::on_channel(0, Message::Data {id: first.wrapping_add(1), data: vec![8]}));
+    sender.poll(Duration::from_millis(1)).unwrap();
+    assert_eq!(peer.recv_frame().unwrap(), Message::on_channel(1, Message::Data {id: first.wrapping_add(2), data: vec![2]}));
+    assert_eq!(sender.npending(), 5);
+  }
+}
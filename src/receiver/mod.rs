@@ -1,2 +1,157 @@
 use super::*;
-mod state;
+use self::state::error::Result::{Value, Error};
+use self::state::Ready;
+use crate::types::socket::ServerSocket;
+use crate::types::transport::Listener;
+pub(crate) mod state;
+
+pub use self::state::error::ReceiverError;
+pub use self::state::{Capacity, REORDER_CAPACITY};
+
+type MyResult<T> = crate::types::MyResult<T, ReceiverError>;
+
+/// Receiver side of the perfect link, accepts senders one at a time and
+/// hands every message to the handler once and in order.
+/// A message is recorded as delivered after the handler returns, so one is handed to the handler
+/// again only if the receiver crashes while the handler runs or cannot record it
+pub struct PerfectLinkReceiver<L: Listener = ServerSocket> {
+  ready_state: Ready<L>,
+  ndelivered: usize,
+}
+
+impl<L: Listener> PerfectLinkReceiver<L> {
+  pub fn bind(src_addr: String) -> MyResult<PerfectLinkReceiver<L>> {
+    match state::bind(src_addr) {
+      Value(ready) => MyResult::Value(PerfectLinkReceiver { ready_state: ready, ndelivered: 0 }),
+      Error(e) => MyResult::Error(e),
+    }
+  }
+
+  /// Serve senders over a listener that is already bound, e.g. to a port picked by the system
+  pub fn listen(listener: L) -> PerfectLinkReceiver<L> {
+    PerfectLinkReceiver { ready_state: state::listen(listener), ndelivered: 0 }
+  }
+
+  /// Receive buffer slots advertised to senders, 0 keeps them from sending until it is raised
+  /// through the capacity handle
+  #[requires(capacity <= REORDER_CAPACITY)]
+  pub fn with_capacity(mut self, capacity: usize) -> Self {
+    self.ready_state.set_capacity(capacity);
+    self
+  }
+
+  /// Handle to change the receive buffer slots while senders are served, e.g. by an
+  /// application that falls behind the handler and catches up later
+  pub fn capacity(&self) -> Capacity {
+    self.ready_state.capacity()
+  }
+
+  /// Crash-recovery mode: the delivered seqs of every link are kept in dir, so a receiver
+  /// restarted with the same directory does not hand a message it recorded to the handler again
+  pub fn with_state_dir<P: AsRef<std::path::Path>>(mut self, dir: P) -> MyResult<Self> {
+    match self.ready_state.persist_to(dir) {
+      Value(_) => MyResult::Value(self),
+      Error(e) => MyResult::Error(e),
+    }
+  }
+
+  /// Number of messages handed to the handler so far
+  #[pure]
+  pub fn ndelivered(&self) -> usize {
+    self.ndelivered
+  }
+
+  /// Serve senders forever. A failing connection is dropped and the next sender is accepted,
+  /// errors are handed to on_error but never end the loop
+  pub fn run<F, E>(&mut self, mut handler: F, mut on_error: E) -> !
+  where F: FnMut(u64, &[u8]), E: FnMut(ReceiverError) {
+    loop {
+      on_error(self.serve(&mut handler));
+    }
+  }
+
+  /// Accept one sender and deliver its messages until the connection fails.
+  /// Every message is handed to the handler, committed, then acknowledged
+  pub fn serve<F>(&mut self, handler: &mut F) -> ReceiverError
+  where F: FnMut(u64, &[u8]) {
+    let mut listening = match self.ready_state.accept() {
+      Value(listening) => listening,
+      Error(e) => return e,
+    };
+    loop {
+      let deliver = match listening.recv() {
+        Value(deliver) => deliver,
+        Error(e) => return e,
+      };
+      handler(deliver.seq(), deliver.data());
+      self.ndelivered += 1;
+      let committed = match deliver.commit() {
+        Value(committed) => committed,
+        Error(e) => return e,
+      };
+      listening = match committed.ack() {
+        Value(listening) => listening,
+        Error(e) => return e,
+      };
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{sync::mpsc, thread, time::{Duration, Instant}};
+
+  use crate::sender::{PerfectLinkSender, RetryPolicy};
+  use crate::types::loopback::{Loopback, LoopbackListener};
+
+  use crate::Link;
+
+  use super::{state, PerfectLinkReceiver};
+
+  #[test]
+  fn test_delivers_across_senders() {
+    let addr = "receiver-across-senders";
+    let (tx, rx) = mpsc::channel();
+    let mut receiver: PerfectLinkReceiver<LoopbackListener> = PerfectLinkReceiver::bind(addr.to_string()).unwrap();
+    thread::spawn(move || {
+      receiver.run(|_, data| tx.send(data.to_vec()).unwrap(), |_| {});
+    });
+    let policy = RetryPolicy::forever(Duration::from_millis(500));
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), policy.clone());
+    sender.send(vec![1]).unwrap();
+    sender.send(vec![2, 2]).unwrap();
+    drop(sender);
+    // The receiver recovers from the closed connection and serves the next sender
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), policy);
+    sender.send(vec![3]).unwrap();
+    let timeout = Duration::from_secs(5);
+    assert_eq!(rx.recv_timeout(timeout).unwrap(), vec![1]);
+    assert_eq!(rx.recv_timeout(timeout).unwrap(), vec![2, 2]);
+    assert_eq!(rx.recv_timeout(timeout).unwrap(), vec![3]);
+  }
+
+  #[test]
+  fn test_slow_application_closes_window() {
+    let addr = "receiver-slow-application";
+    let (tx, rx) = mpsc::channel();
+    // Busy from the start, nothing is delivered until the application catches up
+    let mut receiver: PerfectLinkReceiver<LoopbackListener> = PerfectLinkReceiver::bind(addr.to_string()).unwrap().with_capacity(0);
+    let capacity = receiver.capacity();
+    thread::spawn(move || {
+      receiver.run(|_, data| tx.send(data[0]).unwrap(), |_| {});
+    });
+    let sj = thread::spawn(move || {
+      let link = Link { src: "slow-src".to_string(), dst: addr.to_string(), capacity: 2 };
+      let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::from_link(&link, RetryPolicy::forever(Duration::from_millis(20)));
+      sender.send_batch((0..5u8).map(|i| vec![i]).collect()).unwrap().len()
+    });
+    let t0 = Instant::now();
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    capacity.set(state::REORDER_CAPACITY);
+    for i in 0..5u8 {
+      assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), i);
+    }
+    assert!(t0.elapsed() >= Duration::from_millis(100));
+    assert_eq!(sj.join().unwrap(), 5);
+  }
+}
@@ -5,9 +5,13 @@ pub enum Result<T> {
     Error(ReceiverError),
 }
 
+#[derive(Clone, Debug)]
 pub enum ReceiverError {
     SocketError,
     RecvError,
+    HandshakeError,
+    /// The delivered seqs could not be written to stable storage, nothing was recorded nor acknowledged
+    StorageError,
 }
 
 
@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use prusti_contracts::*;
+
+use crate::messaging::Message;
+use super::error::ReceiverError::*;
+use super::error::Result::{self, *};
+use super::registry::Registry;
+use super::Capacity;
+
+/// Receiving half of a link whose transport also carries data the other way, as in Duplex.
+/// It owns no socket: the owner of the transport hands it every frame of the peer and sends
+/// the acks it owes, alone or riding on outgoing data.
+/// Like Listening it delivers every seq once and in order, messages past a gap are left to
+/// the peer's retransmissions
+pub struct Inbound {
+  // Initial sequence number of the peer, its repeated announcements are acknowledged again
+  isn: u64,
+  registry: Registry,
+  // Messages received in order that were not taken out yet
+  inbox: VecDeque<(u64, Vec<u8>)>,
+  capacity: Capacity,
+  // How long a new ack may wait for outgoing data to ride on
+  ack_delay: Duration,
+  // When the owed ack has to leave, None if nothing is owed
+  ack_due: Option<Instant>,
+}
+
+impl Inbound {
+  pub fn new(isn: u64, registry: Registry, capacity: Capacity, ack_delay: Duration) -> Self {
+    Inbound {isn, registry, inbox: VecDeque::new(), capacity, ack_delay, ack_due: None}
+  }
+
+  #[pure]
+  pub fn isn(&self) -> u64 {
+    self.isn
+  }
+
+  /// Free inbox slots, advertised with every ack
+  #[pure]
+  #[trusted]
+  pub fn window(&self) -> u32 {
+    self.capacity.get().saturating_sub(self.inbox.len()) as u32
+  }
+
+  #[pure]
+  #[trusted]
+  pub fn is_empty(&self) -> bool {
+    self.inbox.is_empty()
+  }
+
+  /// When the owed ack has to be sent alone at the latest, None if no ack is owed
+  pub fn ack_due(&self) -> Option<Instant> {
+    self.ack_due
+  }
+
+  /// Cumulative ack and window to send now, alone or with data. Nothing is owed afterwards
+  #[ensures(self.ack_due().is_none())]
+  pub fn take_ack(&mut self) -> (u64, u32) {
+    self.ack_due = None;
+    (self.registry.acked(), self.window())
+  }
+
+  /// Next message in order
+  pub fn pop(&mut self) -> Option<(u64, Vec<u8>)> {
+    self.inbox.pop_front()
+  }
+
+  /// Take in a frame of the peer. Acks for the opposite direction are left to its sender,
+  /// a new announcement of the peer is a HandshakeError
+  pub fn handle(&mut self, msg: Message) -> Result<()> {
+    match msg {
+      Message::Data {id, data} | Message::DataAck {id, data, ..} => {
+        self.accept(id, data);
+        Value(())
+      },
+      // The peer missed our handshake ack
+      Message::Connect {id} if id == self.isn => {
+        self.ack_due = Some(Instant::now());
+        Value(())
+      },
+      Message::Connect {..} | Message::Resume {..} => Error(HandshakeError),
+      Message::Ack {..} | Message::Sack {..} | Message::Probe {..} | Message::Refuse {..} | Message::Channel {..} => Value(()),
+    }
+  }
+
+  /// Keep the next message in order, anything else is only acknowledged again.
+  /// Nothing is acknowledged while the inbox is full, the peer retransmits later
+  fn accept(&mut self, seq: u64, data: Vec<u8>) {
+    if self.registry.contains(seq) {
+      // Our ack was lost, repeat it right away
+      self.ack_due = Some(Instant::now());
+      return;
+    }
+    if seq != self.registry.next() || self.window() == 0 {
+      return;
+    }
+    self.registry.insert(seq);
+    self.inbox.push_back((seq, data));
+    if self.ack_due.is_none() {
+      self.ack_due = Some(Instant::now() + self.ack_delay);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+
+  use crate::messaging::Message;
+  use super::super::registry::Registry;
+  use super::super::Capacity;
+  use super::Inbound;
+
+  #[test]
+  fn test_inbound_delays_new_acks_only() {
+    let mut inbound = Inbound::new(10, Registry::new(11), Capacity::new(1), Duration::from_secs(1));
+    assert_eq!(inbound.take_ack(), (10, 1));
+    inbound.handle(Message::DataAck {id: 11, data: vec![1], ack: 0, window: 0}).unwrap();
+    assert!(inbound.ack_due().unwrap() > Instant::now());
+    // Full inbox, 12 is neither kept nor acknowledged
+    inbound.handle(Message::Data {id: 12, data: vec![2]}).unwrap();
+    assert_eq!(inbound.take_ack(), (11, 0));
+    // A retransmission is acknowledged again right away
+    inbound.handle(Message::Data {id: 11, data: vec![1]}).unwrap();
+    assert!(inbound.ack_due().unwrap() <= Instant::now());
+    assert_eq!(inbound.pop(), Some((11, vec![1])));
+    assert!(inbound.pop().is_none() && inbound.is_empty());
+    assert!(inbound.handle(Message::Connect {id: 10}).is_ok());
+    assert!(inbound.handle(Message::Connect {id: 3}).is_err());
+  }
+}
@@ -1,76 +1,459 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
 use super::*;
-mod error;
+pub mod error;
+pub mod inbound;
+pub(crate) mod registry;
+use crate::messaging::Message;
 use crate::types::array::Array;
 use crate::types::socket::*;
+use crate::types::storage::{FileStorage, Storage};
+use crate::types::transport::{Listener, Transport};
 use self::{error::*, types::MyResult};
 use self::error::ReceiverError::*;
 use self::error::Result::{self, *};
-pub struct Ready {
-  socket: ServerSocket 
+use crate::types::{next_seq, Packet};
+use self::inbound::Inbound;
+use self::registry::Registry;
+
+/// Out-of-order messages held per connection, frames further ahead are dropped
+/// and left to the sender's retransmissions. Also the largest window ever advertised
+pub const REORDER_CAPACITY: usize = 64;
+/// Senders whose registry a receiver remembers after their connection closed, oldest forgotten first
+pub const MAX_LINKS: usize = 1024;
+
+/// Receive buffer slots the application allows, shared by a receiver and every connection
+/// it accepted so that it can be changed while they are served, e.g. from another thread.
+/// Acks advertise it less the buffered messages. At 0 the window is closed: in-order messages
+/// are refused as well and senders probe until it is raised again
+#[derive(Clone, Debug)]
+pub struct Capacity(Arc<AtomicUsize>);
+
+impl Capacity {
+  #[requires(slots <= REORDER_CAPACITY)]
+  pub fn new(slots: usize) -> Self {
+    Capacity(Arc::new(AtomicUsize::new(slots)))
+  }
+
+  #[pure]
+  #[trusted]
+  #[ensures(result <= REORDER_CAPACITY)]
+  pub fn get(&self) -> usize {
+    self.0.load(Ordering::SeqCst)
+  }
+
+  /// Senders learn the new value from the next ack, or from the answer to their probe
+  #[trusted]
+  #[requires(slots <= REORDER_CAPACITY)]
+  pub fn set(&self, slots: usize) {
+    self.0.store(slots, Ordering::SeqCst);
+  }
 }
 
-pub struct Listening {
-  socket: Socket,
-  // buffer: Array<u8>
+/// Registries of the links served so far by initial sequence number, shared by every connection
+/// of a receiver. A crash-recovery sender resumes its link with the same isn and replays
+/// unacknowledged messages, they are deduplicated against what the link delivered before.
+/// With stable storage every registry is also stored there, one key per link,
+/// so a restarted receiver deduplicates as well
+#[derive(Default)]
+struct Links {
+  registries: HashMap<u64, Registry>,
+  // Oldest first
+  order: VecDeque<u64>,
+  storage: Option<Box<dyn Storage + Send>>,
 }
 
-pub struct Deliver {
-  socket: Socket,
-  data: u8
+impl Links {
+  /// Registry of isn, a fresh one if the link was forgotten or never seen
+  fn registry(&self, isn: u64) -> Result<Registry> {
+    match self.known(isn) {
+      Value(Some(registry)) => Value(registry),
+      Value(None) => Value(Registry::new(next_seq(isn))),
+      Error(e) => Error(e),
+    }
+  }
+
+  /// Registry of a link served before, None if it was forgotten or never seen.
+  /// A StorageError if the stored registry cannot be read or decoded, a link whose delivered seqs
+  /// are unknown must not be taken for a new one
+  fn known(&self, isn: u64) -> Result<Option<Registry>> {
+    if let Some(registry) = self.registries.get(&isn) {
+      return Value(Some(registry.clone()));
+    }
+    let stored = match &self.storage {
+      Some(storage) => storage.retrieve(&Links::key(isn)),
+      None => MyResult::Value(None),
+    };
+    match stored {
+      MyResult::Value(Some(buf)) => match Registry::decode(&buf) {
+        Some(registry) => Value(Some(registry)),
+        None => Error(StorageError),
+      },
+      MyResult::Value(None) => Value(None),
+      MyResult::Error(_) => Error(StorageError),
+    }
+  }
+
+  /// Remember the registry of isn, in stable storage first if there is one
+  fn save(&mut self, isn: u64, registry: &Registry) -> Result<()> {
+    if let Some(storage) = &mut self.storage {
+      if storage.store(&Links::key(isn), &registry.encode()).is_err() {
+        return Error(StorageError);
+      }
+    }
+    if self.registries.insert(isn, registry.clone()).is_none() {
+      self.order.push_back(isn);
+    }
+    if self.order.len() > MAX_LINKS {
+      let oldest = self.order.pop_front().unwrap();
+      self.registries.remove(&oldest);
+    }
+    Value(())
+  }
+
+  fn key(isn: u64) -> String {
+    format!("{:016x}.registry", isn)
+  }
+}
+
+pub struct Ready<L: Listener = ServerSocket> {
+  socket: L,
+  // Buffer slots of every accepted connection
+  capacity: Capacity,
+  links: Arc<Mutex<Links>>,
+}
+
+pub struct Listening<T: Transport = Socket> {
+  socket: T,
+  // Initial sequence number announced by the sender during the handshake
+  isn: u64,
+  // Sequence numbers already delivered from this sender
+  registry: Registry,
+  // Messages received past a gap, waiting for their turn to be delivered
+  buffer: Vec<Packet>,
+  // Slots of the buffer the application allows, at most REORDER_CAPACITY
+  capacity: Capacity,
+  links: Arc<Mutex<Links>>,
+}
+
+pub struct Deliver<T: Transport = Socket> {
+  socket: T,
+  isn: u64,
+  registry: Registry,
+  buffer: Vec<Packet>,
+  capacity: Capacity,
+  links: Arc<Mutex<Links>>,
+  seq: u64,
+  data: Vec<u8>
+}
+
+/// A message the application took and that is recorded as delivered, to acknowledge
+pub struct Committed<T: Transport = Socket> {
+  socket: T,
+  isn: u64,
+  registry: Registry,
+  buffer: Vec<Packet>,
+  capacity: Capacity,
+  links: Arc<Mutex<Links>>,
+  seq: u64,
 }
 
 
-pub fn bind(src_addr: String) -> Result<Ready> {
-  let socket = ServerSocket::bind(src_addr);
+pub fn bind<L: Listener>(src_addr: String) -> Result<Ready<L>> {
+  let socket = L::bind(src_addr);
   match socket {
-    MyResult::Value(socket) => Value(Ready {socket}),
+    MyResult::Value(socket) => Value(listen(socket)),
     MyResult::Error(_) => Error(SocketError)
   }
 }
 
-impl Ready {
-  fn accept(&self) -> Result<Listening> {
+/// Accept senders on a listener that is already bound
+pub fn listen<L: Listener>(socket: L) -> Ready<L> {
+  Ready {socket, capacity: Capacity::new(REORDER_CAPACITY), links: Arc::new(Mutex::new(Links::default()))}
+}
+
+impl<L: Listener> Ready<L> {
+  /// Buffer slots of the connections served, the handshake ack already advertises them
+  #[requires(capacity <= REORDER_CAPACITY)]
+  pub fn set_capacity(&mut self, capacity: usize) {
+    self.capacity.set(capacity);
+  }
+
+  /// Handle to change the buffer slots while connections are served
+  pub fn capacity(&self) -> Capacity {
+    self.capacity.clone()
+  }
+
+  /// Keep the delivered seqs of every link in storage. A receiver restarted with the same
+  /// storage never delivers a seq twice, a seq is stored before its ack leaves
+  pub fn persist_with<S: Storage + Send + 'static>(&mut self, storage: S) {
+    self.links.lock().unwrap().storage = Some(Box::new(storage));
+  }
+
+  /// persist_with a FileStorage in dir, created if needed
+  pub fn persist_to<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+    match FileStorage::open(dir) {
+      MyResult::Value(storage) => {
+        self.persist_with(storage);
+        Value(())
+      },
+      MyResult::Error(_) => Error(StorageError)
+    }
+  }
+
+  /// Accept a connection and complete the handshake,
+  /// the returned Listening state knows the first sequence number to expect.
+  /// A link announced with a known isn continues where its last connection left off,
+  /// resuming a link that was forgotten fails with HandshakeError. A link whose stored registry
+  /// cannot be read fails with StorageError, in either case the sender is sent Refuse
+  pub fn accept(&self) -> Result<Listening<L::Transport>> {
     let s = self.socket.accept();
     match s {
-      MyResult::Value(socket) => Value(Listening {socket}),
+      MyResult::Value(socket) => handshake(socket, self.capacity.clone(), self.links.clone()),
       MyResult::Error(_) => Error(SocketError)
     }
   }
 }
 
-impl Listening {
-  fn recv(mut self) -> Result<Deliver> {
-      let res = self.socket.recv();
-      match res {
-        MyResult::Value(data) => Value(Deliver {socket: self.socket, data}),
-        MyResult::Error(_) => return Error(RecvError)
-    }
+/// Complete the handshake of a transport accepted elsewhere, e.g. for a Duplex.
+/// The link is new, it shares no registry with the links of a receiver
+pub fn accept_on<T: Transport>(socket: T, capacity: Capacity) -> Result<Listening<T>> {
+  handshake(socket, capacity, Arc::new(Mutex::new(Links::default())))
+}
+
+#[ensures(result.is_ok() ==> !crate::types::seq_lt(result.unwrap().registry().next(), next_seq(result.unwrap().isn())))]
+fn handshake<T: Transport>(mut socket: T, capacity: Capacity, links: Arc<Mutex<Links>>) -> Result<Listening<T>> {
+  let res = socket.recv_frame();
+  let (id, registry) = match res {
+    MyResult::Value(Message::Connect {id}) => (id, links.lock().unwrap().registry(id)),
+    // A link resumed over a new connection
+    MyResult::Value(Message::Resume {id, seq}) => {
+      let registry = match links.lock().unwrap().known(id) {
+        Value(Some(mut registry)) => {
+          registry.skip_to(seq);
+          Value(registry)
+        },
+        Value(None) => Error(HandshakeError),
+        Error(e) => Error(e),
+      };
+      (id, registry)
+    },
+    MyResult::Value(_) => return Error(HandshakeError),
+    MyResult::Error(_) => return Error(RecvError)
+  };
+  let registry = match registry {
+    Value(registry) => registry,
+    // Tell the sender to start a fresh link, the connection is dropped either way
+    Error(e) => {
+      let _ = socket.send_frame(Message::Refuse {id});
+      return Error(e);
+    },
+  };
+  match socket.send_frame(Message::Ack {id, window: capacity.get() as u32}) {
+    MyResult::Value(_) => Value(Listening {socket, isn: id, registry, buffer: Vec::new(), capacity, links}),
+    MyResult::Error(_) => Error(SocketError)
   }
 }
 
-impl Deliver {
-  fn deliver(mut self) -> Result<Listening> {
-    let res = self.socket.send(self.data);
-    match res {
-      MyResult::Value(_) => Value(Listening {socket: self.socket}),
+impl<T: Transport> Listening<T> {
+  #[pure]
+  fn isn(&self) -> u64 {
+    self.isn
+  }
+
+  #[pure]
+  fn registry(&self) -> &Registry {
+    &self.registry
+  }
+
+  #[pure]
+  #[trusted]
+  pub fn nbuffered(&self) -> usize {
+    self.buffer.len()
+  }
+
+  /// Free slots of the buffer, advertised in every ack
+  #[pure]
+  #[trusted]
+  pub fn window(&self) -> u32 {
+    self.capacity.get().saturating_sub(self.buffer.len()) as u32
+  }
+
+  /// Resize the buffer, a slow application closes the window with 0 and reopens it later.
+  /// The capacity is shared, see Capacity
+  #[requires(capacity <= REORDER_CAPACITY)]
+  pub fn set_capacity(&mut self, capacity: usize) {
+    self.capacity.set(capacity);
+  }
+
+  /// Handle to resize the buffer from elsewhere, e.g. while blocked in recv
+  pub fn capacity(&self) -> Capacity {
+    self.capacity.clone()
+  }
+
+  /// Hand the transport back and keep receiving through an Inbound, for a link that sends data
+  /// over the same transport. Messages buffered past a gap are dropped, the peer retransmits them
+  pub fn into_inbound(self, ack_delay: Duration) -> (T, Inbound) {
+    (self.socket, Inbound::new(self.isn, self.registry, self.capacity, ack_delay))
+  }
+
+  /// Hand the transport back with the initial sequence number of the link, for a receiver
+  /// that keeps its own registries, e.g. one per channel of a MuxReceiver
+  pub fn into_transport(self) -> (T, u64) {
+    (self.socket, self.isn)
+  }
+
+  /// Cumulative ack of everything delivered so far, with the current window
+  fn ack(&mut self) -> Result<()> {
+    match self.socket.send_frame(Message::Ack {id: self.registry.acked(), window: self.window()}) {
+      MyResult::Value(_) => Value(()),
+      MyResult::Error(_) => Error(SocketError)
+    }
+  }
+
+  /// Wait for the next message in seq order, messages that arrive early are buffered
+  /// and handed out once the gap before them is filled. Nothing is handed out while the
+  /// capacity is 0, in-order messages are answered with an ack of the closed window instead.
+  /// Retransmissions of delivered messages are acknowledged again but never handed out,
+  /// together with Deliver::commit this hands every seq out once and in order.
+  /// Acks are cumulative, they carry the highest seq up to which everything was delivered
+  /// and the window, buffered messages are confirmed one by one with a Sack
+  #[ensures(result.is_ok() ==> !result.unwrap().registry().contains(result.unwrap().seq()))]
+  #[ensures(result.is_ok() ==> result.unwrap().seq() == result.unwrap().registry().next())]
+  pub fn recv(mut self) -> Result<Deliver<T>> {
+    loop {
+      let next = self.registry.next();
+      let closed = self.capacity.get() == 0;
+      if let Some(i) = self.buffer.iter().position(|p| p.seq() == next).filter(|_| !closed) {
+        let pkt = self.buffer.swap_remove(i);
+        return Value(self.into_deliver(next, pkt.into_data()));
+      }
+      let res = self.socket.recv_frame();
+      let handled = match res {
+        // The ack was lost and the sender retransmitted
+        MyResult::Value(Message::Data {id, ..}) if self.registry.contains(id) => self.ack(),
+        // The capacity may have changed while waiting for the frame
+        MyResult::Value(Message::Data {id, ..}) if id == next && self.capacity.get() == 0 => self.ack(),
+        MyResult::Value(Message::Data {id, data}) if id == next => return Value(self.into_deliver(id, data)),
+        MyResult::Value(Message::Data {id, data}) => self.hold(id, data),
+        // The ack of the handshake was lost, confirm the initial sequence number again
+        MyResult::Value(Message::Connect {id}) | MyResult::Value(Message::Resume {id, ..}) if id == self.isn => {
+          match self.socket.send_frame(Message::Ack {id, window: self.window()}) {
+            MyResult::Value(_) => Value(()),
+            MyResult::Error(_) => Error(SocketError)
+          }
+        },
+        MyResult::Value(Message::Connect {..}) | MyResult::Value(Message::Resume {..}) => Error(HandshakeError),
+        // The sender waits for the window to reopen
+        MyResult::Value(Message::Probe {..}) => self.ack(),
+        // Acks only travel from the receiver to the sender, duplex and channel frames belong to other protocols
+        MyResult::Value(Message::Ack {..}) | MyResult::Value(Message::Sack {..}) | MyResult::Value(Message::Refuse {..}) |
+        MyResult::Value(Message::DataAck {..}) | MyResult::Value(Message::Channel {..}) => Value(()),
+        MyResult::Error(_) => Error(RecvError)
+      };
+      if let Error(e) = handled {
+        return Error(e);
+      }
+    }
+  }
+
+  /// Buffer a message received past a gap and confirm it with a Sack.
+  /// Messages more than REORDER_CAPACITY seqs ahead, or arriving while the window is closed,
+  /// are dropped without an ack
+  fn hold(&mut self, seq: u64, data: Vec<u8>) -> Result<()> {
+    let buffered = self.buffer.iter().any(|p| p.seq() == seq);
+    let ahead = seq.wrapping_sub(self.registry.next());
+    if !buffered {
+      if ahead >= REORDER_CAPACITY as u64 || self.window() == 0 {
+        return Value(());
+      }
+      self.buffer.push(Packet::new(seq, data));
+    }
+    match self.socket.send_frame(Message::Sack {id: seq}) {
+      MyResult::Value(_) => Value(()),
       MyResult::Error(_) => Error(SocketError)
     }
   }
+
+  fn into_deliver(self, seq: u64, data: Vec<u8>) -> Deliver<T> {
+    Deliver {socket: self.socket, isn: self.isn, registry: self.registry, buffer: self.buffer, capacity: self.capacity, links: self.links, seq, data}
+  }
+}
+
+impl<T: Transport> Deliver<T> {
+  #[pure]
+  pub fn seq(&self) -> u64 {
+    self.seq
+  }
+
+  pub fn data(&self) -> &[u8] {
+    &self.data
+  }
+
+  #[pure]
+  fn registry(&self) -> &Registry {
+    &self.registry
+  }
+
+  /// Record the message as delivered once the application took it: the seq is persisted if the
+  /// receiver has a state directory, then registered. So a retransmission is never handed out again,
+  /// not even over a later connection of the same link, while a message whose application crashed
+  /// before commit is handed out again after the restart.
+  /// On a StorageError nothing is recorded nor acknowledged, the sender retransmits and the message
+  /// is handed out again
+  #[ensures(result.is_ok() ==> result.unwrap().registry().contains(old(self.seq)))]
+  pub fn commit(self) -> Result<Committed<T>> {
+    let mut registry = self.registry.clone();
+    registry.insert(self.seq);
+    if let Error(e) = self.links.lock().unwrap().save(self.isn, &registry) {
+      return Error(e);
+    }
+    Value(Committed {socket: self.socket, isn: self.isn, registry, buffer: self.buffer, capacity: self.capacity, links: self.links, seq: self.seq})
+  }
+}
+
+impl<T: Transport> Committed<T> {
+  #[pure]
+  pub fn seq(&self) -> u64 {
+    self.seq
+  }
+
+  #[pure]
+  fn registry(&self) -> &Registry {
+    &self.registry
+  }
+
+  /// Send a cumulative ack with the window once the message is recorded
+  #[ensures(result.is_ok() ==> result.unwrap().registry().contains(old(self.seq)))]
+  pub fn ack(self) -> Result<Listening<T>> {
+    let mut listening = Listening {socket: self.socket, isn: self.isn, registry: self.registry, buffer: self.buffer, capacity: self.capacity, links: self.links};
+    match listening.ack() {
+      Value(_) => Value(listening),
+      Error(e) => Error(e)
+    }
+  }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use std::{thread, time::Duration};
+    use std::thread;
 
-    use crate::types::MyResult;
+    use crate::{messaging::Message, types::MyResult};
+    use crate::types::loopback::{Loopback, LoopbackListener};
+    use crate::types::storage::{Storage, StorageError};
+    use crate::types::transport::Transport;
 
-    use super::{bind, error::Result, Socket};
+    use super::{bind, error::{ReceiverError, Result}, REORDER_CAPACITY};
 
   #[test]
   fn test_receiver_protocol() {
-    let src_addr = "localhost:8080".to_string();
-    let receiver = bind(src_addr.clone()).unwrap();
+    let src_addr = "receiver-protocol".to_string();
+    let receiver = bind::<LoopbackListener>(src_addr.clone()).unwrap();
     let sj = thread::spawn(move || {
       run_client(src_addr)
     });
@@ -79,26 +462,283 @@ mod tests {
     match r {
       Result::Value(deliver) => {
         let data = deliver.data.clone();
-        match deliver.deliver() {
-          Result::Value(_) => print!("Delivered for data {}\n", data),
-          Result::Error(_) => print!("Error when delivering"),
+        assert_eq!(data, vec![10]);
+        let committed = match deliver.commit() {
+          Result::Value(committed) => committed,
+          Result::Error(_) => panic!("Error when committing"),
+        };
+        match committed.ack() {
+          Result::Value(_) => print!("Delivered for data {:?}\n", data),
+          Result::Error(_) => panic!("Error when delivering"),
         }
       },
-      Result::Error(_) => print!("Error when receiving..\n")
+      Result::Error(_) => panic!("Error when receiving..\n")
     };
-    sj.join();
+    sj.join().unwrap();
+  }
+
+  #[test]
+  fn test_duplicate_is_acked_not_delivered() {
+    let src_addr = "receiver-duplicate".to_string();
+    let receiver = bind::<LoopbackListener>(src_addr.clone()).unwrap();
+    let mut s = Loopback::connect(src_addr).unwrap();
+    s.send_frame(Message::Connect {id: 0}).unwrap();
+    s.send_frame(Message::Data {id: 1, data: vec![1]}).unwrap();
+    s.send_frame(Message::Data {id: 1, data: vec![1]}).unwrap();
+    s.send_frame(Message::Data {id: 2, data: vec![2]}).unwrap();
+    let listening = receiver.accept().unwrap();
+    let deliver = listening.recv().unwrap();
+    assert_eq!(deliver.seq(), 1);
+    let deliver = deliver.commit().unwrap().ack().unwrap().recv().unwrap();
+    assert_eq!(deliver.seq(), 2);
+    deliver.commit().unwrap().ack().unwrap();
+    let window = REORDER_CAPACITY as u32;
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 0, window});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 1, window});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 1, window});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 2, window});
+  }
+
+  #[test]
+  fn test_persisted_seqs_survive_restart() {
+    let dir = std::env::temp_dir().join(format!("plink-receiver-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let window = REORDER_CAPACITY as u32;
+    let mut receiver = bind::<LoopbackListener>("receiver-persist-1".to_string()).unwrap();
+    receiver.persist_to(&dir).unwrap();
+    let mut s = Loopback::connect("receiver-persist-1".to_string()).unwrap();
+    s.send_frame(Message::Connect {id: 5}).unwrap();
+    s.send_frame(Message::Data {id: 6, data: vec![6]}).unwrap();
+    s.send_frame(Message::Data {id: 7, data: vec![7]}).unwrap();
+    let listening = receiver.accept().unwrap();
+    let listening = listening.recv().unwrap().commit().unwrap().ack().unwrap();
+    listening.recv().unwrap().commit().unwrap().ack().unwrap();
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 5, window});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 6, window});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 7, window});
+    // The receiver crashes before the sender sees the last ack, its successor only has the directory
+    drop(receiver);
+    let mut receiver = bind::<LoopbackListener>("receiver-persist-2".to_string()).unwrap();
+    receiver.persist_to(&dir).unwrap();
+    let mut s = Loopback::connect("receiver-persist-2".to_string()).unwrap();
+    s.send_frame(Message::Connect {id: 5}).unwrap();
+    s.send_frame(Message::Data {id: 7, data: vec![7]}).unwrap();
+    s.send_frame(Message::Data {id: 8, data: vec![8]}).unwrap();
+    let deliver = receiver.accept().unwrap().recv().unwrap();
+    assert_eq!(deliver.seq(), 8);
+    deliver.commit().unwrap().ack().unwrap();
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 5, window});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 7, window});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 8, window});
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_unreadable_registry_refuses_link() {
+    let dir = std::env::temp_dir().join(format!("plink-receiver-corrupt-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join(format!("{:016x}.registry", 5)), [1, 2, 3]).unwrap();
+    let mut receiver = bind::<LoopbackListener>("receiver-corrupt".to_string()).unwrap();
+    receiver.persist_to(&dir).unwrap();
+    // Neither a new connection nor a resumed one of link 5 is taken for a fresh link
+    for announcement in [Message::Connect {id: 5}, Message::Resume {id: 5, seq: 6}] {
+      let mut s = Loopback::connect("receiver-corrupt".to_string()).unwrap();
+      s.send_frame(announcement).unwrap();
+      match receiver.accept() {
+        Result::Error(ReceiverError::StorageError) => {},
+        _ => panic!("Expected the link to be refused\n"),
+      }
+      assert_eq!(s.recv_frame().unwrap(), Message::Refuse {id: 5});
+      assert!(s.recv_frame().is_err());
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  /// Refuses every write, as a full disk would
+  struct FullStorage;
+
+  impl Storage for FullStorage {
+    fn holds(&self, _key: &str, _value: &[u8]) -> bool {
+      false
+    }
+
+    fn contains(&self, _key: &str) -> bool {
+      false
+    }
+
+    fn store(&mut self, _key: &str, _value: &[u8]) -> MyResult<(), StorageError> {
+      MyResult::Error(StorageError::WriteError)
+    }
+
+    fn retrieve(&self, _key: &str) -> MyResult<Option<Vec<u8>>, StorageError> {
+      MyResult::Value(None)
+    }
+  }
+
+  #[test]
+  fn test_crash_before_commit_redelivers() {
+    let dir = std::env::temp_dir().join(format!("plink-receiver-commit-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let window = REORDER_CAPACITY as u32;
+    let mut receiver = bind::<LoopbackListener>("receiver-commit-1".to_string()).unwrap();
+    receiver.persist_to(&dir).unwrap();
+    let mut s = Loopback::connect("receiver-commit-1".to_string()).unwrap();
+    s.send_frame(Message::Connect {id: 5}).unwrap();
+    s.send_frame(Message::Data {id: 6, data: vec![6]}).unwrap();
+    let deliver = receiver.accept().unwrap().recv().unwrap();
+    assert_eq!(deliver.data(), &[6]);
+    // The handler did not return before the crash, the message is handed out again
+    drop(deliver);
+    drop(receiver);
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 5, window});
+    assert!(s.recv_frame().is_err());
+    let mut receiver = bind::<LoopbackListener>("receiver-commit-2".to_string()).unwrap();
+    receiver.persist_to(&dir).unwrap();
+    let mut s = Loopback::connect("receiver-commit-2".to_string()).unwrap();
+    s.send_frame(Message::Connect {id: 5}).unwrap();
+    s.send_frame(Message::Data {id: 6, data: vec![6]}).unwrap();
+    let deliver = receiver.accept().unwrap().recv().unwrap();
+    assert_eq!(deliver.seq(), 6);
+    assert_eq!(deliver.data(), &[6]);
+    deliver.commit().unwrap().ack().unwrap();
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 5, window});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 6, window});
+    std::fs::remove_dir_all(&dir).unwrap();
+    // Nothing is acknowledged unless it was recorded
+    let mut receiver = bind::<LoopbackListener>("receiver-commit-3".to_string()).unwrap();
+    receiver.persist_with(FullStorage);
+    let mut s = Loopback::connect("receiver-commit-3".to_string()).unwrap();
+    s.send_frame(Message::Connect {id: 9}).unwrap();
+    s.send_frame(Message::Data {id: 10, data: vec![10]}).unwrap();
+    match receiver.accept().unwrap().recv().unwrap().commit() {
+      Result::Error(ReceiverError::StorageError) => {},
+      _ => panic!("Expected the commit to fail\n"),
+    }
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 9, window});
+    assert!(s.recv_frame().is_err());
+  }
+
+  #[test]
+  fn test_resume_gives_up_abandoned_seqs() {
+    let src_addr = "receiver-resume".to_string();
+    let receiver = bind::<LoopbackListener>(src_addr.clone()).unwrap();
+    let window = REORDER_CAPACITY as u32;
+    let mut s = Loopback::connect(src_addr.clone()).unwrap();
+    s.send_frame(Message::Connect {id: 20}).unwrap();
+    s.send_frame(Message::Data {id: 21, data: vec![1]}).unwrap();
+    receiver.accept().unwrap().recv().unwrap().commit().unwrap().ack().unwrap();
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 20, window});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 21, window});
+    // 22 was lost with the connection and the sender gave up on it
+    let mut s = Loopback::connect(src_addr.clone()).unwrap();
+    s.send_frame(Message::Resume {id: 20, seq: 23}).unwrap();
+    s.send_frame(Message::Data {id: 23, data: vec![3]}).unwrap();
+    let deliver = receiver.accept().unwrap().recv().unwrap();
+    assert_eq!(deliver.seq(), 23);
+    deliver.commit().unwrap().ack().unwrap();
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 20, window});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 23, window});
+    // A link the receiver never served is refused
+    let mut s = Loopback::connect(src_addr).unwrap();
+    s.send_frame(Message::Resume {id: 7, seq: 8}).unwrap();
+    match receiver.accept() {
+      Result::Error(ReceiverError::HandshakeError) => {},
+      _ => panic!("Expected the resume to be refused\n"),
+    }
+    assert_eq!(s.recv_frame().unwrap(), Message::Refuse {id: 7});
+    assert!(s.recv_frame().is_err());
+  }
+
+  #[test]
+  fn test_reorders_and_acks_selectively() {
+    let src_addr = "receiver-reorder".to_string();
+    let receiver = bind::<LoopbackListener>(src_addr.clone()).unwrap();
+    let mut s = Loopback::connect(src_addr).unwrap();
+    s.send_frame(Message::Connect {id: 10}).unwrap();
+    s.send_frame(Message::Data {id: 12, data: vec![2]}).unwrap();
+    s.send_frame(Message::Data {id: 12, data: vec![2]}).unwrap();
+    // Past the reorder buffer, dropped
+    s.send_frame(Message::Data {id: 11 + REORDER_CAPACITY as u64, data: vec![0]}).unwrap();
+    s.send_frame(Message::Data {id: 11, data: vec![1]}).unwrap();
+    let listening = receiver.accept().unwrap();
+    let deliver = listening.recv().unwrap();
+    assert_eq!(deliver.seq(), 11);
+    let deliver = deliver.commit().unwrap().ack().unwrap().recv().unwrap();
+    assert_eq!(deliver.seq(), 12);
+    assert_eq!(deliver.data(), &[2]);
+    assert_eq!(deliver.commit().unwrap().ack().unwrap().nbuffered(), 0);
+    let window = REORDER_CAPACITY as u32;
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 10, window});
+    assert_eq!(s.recv_frame().unwrap(), Message::Sack {id: 12});
+    assert_eq!(s.recv_frame().unwrap(), Message::Sack {id: 12});
+    // 12 is still buffered when 11 is acknowledged
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 11, window: window - 1});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 12, window});
+  }
+
+  #[test]
+  fn test_window_closes_and_probe_reopens() {
+    let src_addr = "receiver-window".to_string();
+    let receiver = bind::<LoopbackListener>(src_addr.clone()).unwrap();
+    let mut s = Loopback::connect(src_addr).unwrap();
+    s.send_frame(Message::Connect {id: 0}).unwrap();
+    s.send_frame(Message::Data {id: 2, data: vec![2]}).unwrap();
+    s.send_frame(Message::Data {id: 3, data: vec![3]}).unwrap();
+    s.send_frame(Message::Data {id: 1, data: vec![1]}).unwrap();
+    s.send_frame(Message::Probe {id: 4}).unwrap();
+    s.send_frame(Message::Probe {id: 4}).unwrap();
+    let listening = receiver.accept().unwrap();
+    let capacity = listening.capacity();
+    capacity.set(1);
+    // Only one slot, 3 is dropped
+    let listening = listening.recv().unwrap().commit().unwrap().ack().unwrap();
+    assert_eq!(listening.window(), 0);
+    // Closed, 2 is held back and the probes learn that the window is closed
+    capacity.set(0);
+    let rj = thread::spawn(move || listening.recv().unwrap().commit().unwrap().ack().unwrap());
+    s.recv_frame().unwrap();
+    assert_eq!(s.recv_frame().unwrap(), Message::Sack {id: 2});
+    for _ in 0..3 {
+      assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 1, window: 0});
+    }
+    // Reopened while the receiver waits, the next probe gets 2 delivered
+    capacity.set(REORDER_CAPACITY);
+    s.send_frame(Message::Probe {id: 4}).unwrap();
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 1, window: REORDER_CAPACITY as u32 - 1});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 2, window: REORDER_CAPACITY as u32});
+    assert_eq!(rj.join().unwrap().window(), REORDER_CAPACITY as u32);
+  }
+
+  #[test]
+  fn test_closed_window_refuses_in_order_data() {
+    let src_addr = "receiver-closed-window".to_string();
+    let mut receiver = bind::<LoopbackListener>(src_addr.clone()).unwrap();
+    receiver.set_capacity(0);
+    let capacity = receiver.capacity();
+    let mut s = Loopback::connect(src_addr).unwrap();
+    s.send_frame(Message::Connect {id: 0}).unwrap();
+    s.send_frame(Message::Data {id: 1, data: vec![1]}).unwrap();
+    let rj = thread::spawn(move || receiver.accept().unwrap().recv().unwrap().commit().unwrap().ack().unwrap());
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 0, window: 0});
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 0, window: 0});
+    capacity.set(1);
+    s.send_frame(Message::Data {id: 1, data: vec![1]}).unwrap();
+    assert_eq!(s.recv_frame().unwrap(), Message::Ack {id: 1, window: 1});
+    rj.join().unwrap();
   }
 
   fn run_client(addr: String) {
-    let data = 10;
-    let mut s = Socket::connect(addr)
+    let data = vec![10];
+    let mut s = Loopback::connect(addr)
       .unwrap();
-    thread::sleep(Duration::from_secs(3));
-    s.send(data);
-    let r = s.recv();
+    s.send_frame(Message::Connect {id: 0}).unwrap();
+    s.recv_frame().unwrap();
+    s.send_frame(Message::Data {id: 1, data: data.clone()}).unwrap();
+    let r = s.recv_frame();
     match r {
-      MyResult::Value(v) => print!("Received ACK {} for data {}\n", v, data),
-      MyResult::Error(_) => print!("SocketError\n")
+      MyResult::Value(v) => assert_eq!(v, Message::Ack {id: 1, window: REORDER_CAPACITY as u32}),
+      MyResult::Error(_) => panic!("SocketError\n")
     }
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,131 @@
+use prusti_contracts::*;
+use crate::types::{next_seq, seq_lt};
+
+/// Sequence numbers already delivered from one sender.
+/// Every seq before next has been delivered, sparse holds the ones delivered past a gap
+#[derive(Clone, Debug)]
+pub struct Registry {
+  next: u64,
+  sparse: Vec<u64>,
+}
+
+impl Registry {
+  /// Registry for a sender whose first message carries seq first
+  #[ensures(result.next() == first)]
+  pub fn new(first: u64) -> Self {
+    Registry {next: first, sparse: Vec::new()}
+  }
+
+  /// Lowest sequence number that has not been delivered yet
+  #[pure]
+  pub fn next(&self) -> u64 {
+    self.next
+  }
+
+  /// Highest seq up to which everything has been delivered, carried by cumulative acks.
+  /// Before any delivery this is the seq preceding first, i.e. the initial sequence number
+  #[pure]
+  #[trusted]
+  #[ensures(next_seq(result) == self.next())]
+  pub fn acked(&self) -> u64 {
+    self.next.wrapping_sub(1)
+  }
+
+  #[pure]
+  #[trusted]
+  pub fn contains(&self, seq: u64) -> bool {
+    seq_lt(seq, self.next) || self.sparse.contains(&seq)
+  }
+
+  /// Stable form of the registry: [next: u64 big endian][n: u32 big endian][n sparse seqs: u64 big endian]
+  pub fn encode(&self) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(12 + 8 * self.sparse.len());
+    buf.extend_from_slice(&self.next.to_be_bytes());
+    buf.extend_from_slice(&(self.sparse.len() as u32).to_be_bytes());
+    for seq in self.sparse.iter() {
+      buf.extend_from_slice(&seq.to_be_bytes());
+    }
+    buf
+  }
+
+  /// None if buf does not hold an encoded registry
+  pub fn decode(buf: &[u8]) -> Option<Registry> {
+    if buf.len() < 12 {
+      return None;
+    }
+    let next = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+    let n = u32::from_be_bytes(buf[8..12].try_into().unwrap()) as usize;
+    if buf.len() != 12 + 8 * n {
+      return None;
+    }
+    let sparse = buf[12..].chunks(8).map(|seq| u64::from_be_bytes(seq.try_into().unwrap())).collect();
+    Some(Registry {next, sparse})
+  }
+
+  /// Record seq as delivered, nothing that was delivered before is forgotten
+  #[trusted]
+  #[ensures(self.contains(seq))]
+  #[ensures(forall(|s: u64| old(self.contains(s)) ==> self.contains(s)))]
+  pub fn insert(&mut self, seq: u64) {
+    if self.contains(seq) {
+      return;
+    }
+    if seq != self.next {
+      self.sparse.push(seq);
+      return;
+    }
+    self.next = next_seq(self.next);
+    self.compact();
+  }
+
+  /// Give up on the seqs before seq that were not delivered, a resumed sender never sends them again
+  #[trusted]
+  #[ensures(!seq_lt(self.next(), seq))]
+  #[ensures(forall(|s: u64| old(self.contains(s)) ==> self.contains(s)))]
+  pub fn skip_to(&mut self, seq: u64) {
+    if !seq_lt(self.next, seq) {
+      return;
+    }
+    self.next = seq;
+    self.sparse.retain(|s| !seq_lt(*s, seq));
+    self.compact();
+  }
+
+  // Move next past the sparse seqs that follow it
+  fn compact(&mut self) {
+    while let Some(i) = self.sparse.iter().position(|s| *s == self.next) {
+      self.sparse.swap_remove(i);
+      self.next = next_seq(self.next);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Registry;
+
+  #[test]
+  fn test_registry_compacts_gaps() {
+    let mut registry = Registry::new(u64::MAX - 1);
+    registry.insert(0);
+    assert!(registry.contains(0));
+    assert!(!registry.contains(u64::MAX));
+    registry.insert(u64::MAX - 1);
+    assert_eq!(registry.acked(), u64::MAX - 1);
+    registry.insert(u64::MAX);
+    assert_eq!(registry.next(), 1);
+    assert_eq!(registry.acked(), 0);
+    assert!(registry.contains(u64::MAX));
+    assert!(!registry.contains(1));
+    registry.insert(5);
+    let decoded = Registry::decode(&registry.encode()).unwrap();
+    assert_eq!((decoded.next(), decoded.contains(5), decoded.contains(4)), (1, true, false));
+    assert!(Registry::decode(&registry.encode()[1..]).is_none());
+    registry.insert(7);
+    registry.skip_to(5);
+    assert_eq!(registry.next(), 6);
+    assert!(registry.contains(2) && registry.contains(7) && !registry.contains(6));
+    registry.skip_to(3);
+    assert_eq!(registry.next(), 6);
+  }
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use prusti_contracts::*;
+
+use crate::duplex::Duplex;
+use crate::sender::RetryPolicy;
+use crate::types::socket::Socket;
+use crate::types::transport::Transport;
+use crate::types::MyResult::{Error, Value};
+use super::{Envelope, RpcError, Status, Wire, MAX_METHOD_LEN};
+
+type MyResult<T> = crate::types::MyResult<T, RpcError>;
+
+/// Calling side of the RPC layer. Every call gets a correlation id, its request is kept
+/// until the reply arrives so it can be retried under the same id
+pub struct RpcClient<T: Transport = Socket> {
+  link: Duplex<T>,
+  next_call: u64,
+  // Encoded requests of the calls without a reply yet
+  pending: HashMap<u64, Vec<u8>>,
+  // Replies that arrived while waiting for another call
+  replies: HashMap<u64, (Status, Vec<u8>)>,
+}
+
+impl<T: Transport> RpcClient<T> {
+  pub fn connect(remote_addr: String, policy: RetryPolicy) -> MyResult<RpcClient<T>> {
+    match Duplex::connect(remote_addr, policy) {
+      Value(link) => Value(RpcClient::from_link(link)),
+      Error(e) => Error(RpcError::Link(e)),
+    }
+  }
+
+  pub fn from_link(link: Duplex<T>) -> Self {
+    RpcClient { link, next_call: 0, pending: HashMap::new(), replies: HashMap::new() }
+  }
+
+  /// Calls made and not answered yet
+  #[pure]
+  #[trusted]
+  pub fn npending(&self) -> usize {
+    self.pending.len()
+  }
+
+  /// Send a request and return its correlation id without waiting for the reply.
+  /// Method names longer than MAX_METHOD_LEN bytes are refused with MethodTooLong
+  pub fn request<Req: Wire>(&mut self, method: &str, req: &Req) -> MyResult<u64> {
+    if method.len() > MAX_METHOD_LEN {
+      return Error(RpcError::MethodTooLong);
+    }
+    let call = self.next_call;
+    let request = Envelope::Request {call, method: method.to_string(), body: req.encode()}.encode();
+    if let Error(e) = self.link.send(request.clone()) {
+      return Error(RpcError::Link(e));
+    }
+    self.next_call += 1;
+    self.pending.insert(call, request);
+    Value(call)
+  }
+
+  /// Send the request of an unanswered call again, e.g. after its deadline passed.
+  /// The server answers a call it already ran from its reply cache
+  pub fn retry(&mut self, call: u64) -> MyResult<()> {
+    let request = match self.pending.get(&call) {
+      Some(request) => request.clone(),
+      None => return Error(RpcError::UnknownCall{call}),
+    };
+    match self.link.send(request) {
+      Value(_) => Value(()),
+      Error(e) => Error(RpcError::Link(e)),
+    }
+  }
+
+  /// Wait up to deadline for the reply of call
+  pub fn wait<Resp: Wire>(&mut self, call: u64, deadline: Duration) -> MyResult<Resp> {
+    if !self.pending.contains_key(&call) {
+      return Error(RpcError::UnknownCall{call});
+    }
+    let end = Instant::now() + deadline;
+    while !self.replies.contains_key(&call) {
+      let remaining = end.saturating_duration_since(Instant::now());
+      if remaining.is_zero() {
+        return Error(RpcError::DeadlineExceeded{call});
+      }
+      let data = match self.link.recv_timeout(remaining) {
+        Value(Some((_, data))) => data,
+        Value(None) => continue,
+        Error(e) => return Error(RpcError::Link(e)),
+      };
+      // Replies to retried calls arrive twice, only the first one is kept
+      if let Some(Envelope::Reply {call, status, body}) = Envelope::decode(&data) {
+        if self.pending.contains_key(&call) {
+          self.replies.insert(call, (status, body));
+        }
+      }
+    }
+    self.pending.remove(&call);
+    let (status, body) = self.replies.remove(&call).unwrap();
+    match status {
+      Status::Ok => match Resp::decode(&body) {
+        Some(resp) => Value(resp),
+        None => Error(RpcError::BadReply),
+      },
+      Status::UnknownMethod => Error(RpcError::UnknownMethod),
+      Status::BadRequest => Error(RpcError::BadRequest),
+      Status::Expired => Error(RpcError::Expired),
+    }
+  }
+
+  /// Acknowledge the replies received so far right away, e.g. before going quiet for a while.
+  /// Otherwise the ack rides on the next request
+  pub fn flush(&mut self) -> MyResult<()> {
+    match self.link.flush() {
+      Value(_) => Value(()),
+      Error(e) => Error(RpcError::Link(e)),
+    }
+  }
+
+  /// Send a request and wait up to deadline for its reply
+  pub fn call<Req: Wire, Resp: Wire>(&mut self, method: &str, req: &Req, deadline: Duration) -> MyResult<Resp> {
+    let end = Instant::now() + deadline;
+    match self.request(method, req) {
+      Value(call) => self.wait(call, end.saturating_duration_since(Instant::now())),
+      Error(e) => Error(e),
+    }
+  }
+}
@@ -0,0 +1,261 @@
+use crate::duplex::DuplexError;
+
+mod client;
+mod server;
+
+pub use self::client::RpcClient;
+pub use self::server::{RpcServer, REPLY_CACHE_CAPACITY};
+
+/// Longest method name in bytes, its length travels in a single byte
+pub const MAX_METHOD_LEN: usize = u8::MAX as usize;
+
+const REQUEST_KIND: u8 = 0x0;
+const REPLY_KIND: u8 = 0x1;
+const CALL_SIZE: usize = 8;
+
+#[derive(Clone, Debug)]
+pub enum RpcError {
+  /// The duplex link under the calls failed
+  Link(DuplexError),
+  /// No reply before the call's deadline, the call can be retried or waited for again
+  DeadlineExceeded{call: u64},
+  /// The call was never made or was already answered
+  UnknownCall{call: u64},
+  UnknownMethod,
+  /// The method name is longer than MAX_METHOD_LEN bytes
+  MethodTooLong,
+  /// The handler could not decode the request
+  BadRequest,
+  /// The reply could not be decoded as the expected type
+  BadReply,
+  /// The server no longer remembers the reply of this retried call, it did run once
+  Expired,
+}
+
+/// How the server answered a call
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+  Ok,
+  UnknownMethod,
+  BadRequest,
+  Expired,
+}
+
+impl Status {
+  fn code(self) -> u8 {
+    match self {
+      Status::Ok => 0,
+      Status::UnknownMethod => 1,
+      Status::BadRequest => 2,
+      Status::Expired => 3,
+    }
+  }
+
+  fn from_code(code: u8) -> Option<Status> {
+    match code {
+      0 => Some(Status::Ok),
+      1 => Some(Status::UnknownMethod),
+      2 => Some(Status::BadRequest),
+      3 => Some(Status::Expired),
+      _ => None,
+    }
+  }
+}
+
+/// Types that travel as request or reply bodies
+pub trait Wire: Sized {
+  fn encode(&self) -> Vec<u8>;
+
+  /// None if bytes do not hold a value of this type
+  fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl Wire for Vec<u8> {
+  fn encode(&self) -> Vec<u8> {
+    self.clone()
+  }
+
+  fn decode(bytes: &[u8]) -> Option<Self> {
+    Some(bytes.to_vec())
+  }
+}
+
+impl Wire for String {
+  fn encode(&self) -> Vec<u8> {
+    self.as_bytes().to_vec()
+  }
+
+  fn decode(bytes: &[u8]) -> Option<Self> {
+    String::from_utf8(bytes.to_vec()).ok()
+  }
+}
+
+impl Wire for u64 {
+  fn encode(&self) -> Vec<u8> {
+    self.to_be_bytes().to_vec()
+  }
+
+  fn decode(bytes: &[u8]) -> Option<Self> {
+    let bytes: [u8; 8] = bytes.try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+  }
+}
+
+impl Wire for () {
+  fn encode(&self) -> Vec<u8> {
+    Vec::new()
+  }
+
+  fn decode(bytes: &[u8]) -> Option<Self> {
+    if bytes.is_empty() { Some(()) } else { None }
+  }
+}
+
+/// Payload of a duplex message:
+/// request [kind: u8][call: u64 big endian][method length: u8][method][body...]
+/// reply   [kind: u8][call: u64 big endian][status: u8][body...]
+#[derive(Clone, Debug, PartialEq)]
+enum Envelope {
+  Request {call: u64, method: String, body: Vec<u8>},
+  Reply {call: u64, status: Status, body: Vec<u8>},
+}
+
+impl Envelope {
+  /// The method of a request is at most MAX_METHOD_LEN bytes, register and request refuse longer names
+  fn encode(self) -> Vec<u8> {
+    let mut buf = Vec::new();
+    match self {
+      Envelope::Request {call, method, body} => {
+        buf.push(REQUEST_KIND);
+        buf.extend_from_slice(&call.to_be_bytes());
+        buf.push(method.len() as u8);
+        buf.extend_from_slice(method.as_bytes());
+        buf.extend_from_slice(&body);
+      },
+      Envelope::Reply {call, status, body} => {
+        buf.push(REPLY_KIND);
+        buf.extend_from_slice(&call.to_be_bytes());
+        buf.push(status.code());
+        buf.extend_from_slice(&body);
+      },
+    }
+    buf
+  }
+
+  fn decode(buf: &[u8]) -> Option<Envelope> {
+    if buf.len() < 2 + CALL_SIZE {
+      return None;
+    }
+    let call = u64::decode(&buf[1..1 + CALL_SIZE])?;
+    let rest = &buf[1 + CALL_SIZE..];
+    match buf[0] {
+      REQUEST_KIND => {
+        let len = rest[0] as usize;
+        if rest.len() < 1 + len {
+          return None;
+        }
+        let method = String::decode(&rest[1..1 + len])?;
+        Some(Envelope::Request {call, method, body: rest[1 + len..].to_vec()})
+      },
+      REPLY_KIND => {
+        let status = Status::from_code(rest[0])?;
+        Some(Envelope::Reply {call, status, body: rest[1..].to_vec()})
+      },
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{thread, time::Duration};
+
+  use crate::sender::RetryPolicy;
+  use crate::types::loopback::{Loopback, LoopbackListener};
+  use crate::types::transport::Listener;
+  use crate::types::MyResult::Error;
+
+  use super::{Envelope, RpcClient, RpcError, RpcServer, Status, MAX_METHOD_LEN};
+
+  #[test]
+  fn test_envelope_roundtrip() {
+    let request = Envelope::Request {call: 7, method: "echo".to_string(), body: vec![1, 2]};
+    assert_eq!(Envelope::decode(&request.clone().encode()), Some(request));
+    let reply = Envelope::Reply {call: u64::MAX, status: Status::BadRequest, body: Vec::new()};
+    assert_eq!(Envelope::decode(&reply.clone().encode()), Some(reply));
+    assert_eq!(Envelope::decode(&[0, 0, 0, 0, 0, 0, 0, 0, 1, 5, b'x']), None);
+  }
+
+  #[test]
+  fn test_typed_calls() {
+    let addr = "rpc-typed";
+    let listener = LoopbackListener::bind(addr.to_string()).unwrap();
+    let server = thread::spawn(move || {
+      let mut server: RpcServer<Loopback> = RpcServer::accept(&listener, RetryPolicy::default()).unwrap();
+      server.register("double", |n: u64| n * 2).unwrap();
+      server.register("greet", |name: String| format!("hello {}", name)).unwrap();
+      assert!(server.register(&"m".repeat(MAX_METHOD_LEN + 1), |n: u64| n).is_err());
+      server.serve()
+    });
+    let mut client: RpcClient<Loopback> = RpcClient::connect(addr.to_string(), RetryPolicy::default()).unwrap();
+    let deadline = Duration::from_secs(1);
+    assert_eq!(client.call::<u64, u64>("double", &21, deadline).unwrap(), 42);
+    assert_eq!(client.call::<String, String>("greet", &"link".to_string(), deadline).unwrap(), "hello link");
+    match client.call::<(), ()>("missing", &(), deadline) {
+      Error(RpcError::UnknownMethod) => {},
+      _ => panic!("Expected an unknown method\n"),
+    }
+    match client.call::<Vec<u8>, String>("greet", &vec![0xff], deadline) {
+      Error(RpcError::BadRequest) => {},
+      _ => panic!("Expected a bad request\n"),
+    }
+    match client.call::<u64, ()>("double", &1, deadline) {
+      Error(RpcError::BadReply) => {},
+      _ => panic!("Expected a bad reply\n"),
+    }
+    match client.call::<(), ()>(&"m".repeat(MAX_METHOD_LEN + 1), &(), deadline) {
+      Error(RpcError::MethodTooLong) => {},
+      _ => panic!("Expected the method name to be refused\n"),
+    }
+    assert_eq!(client.npending(), 0);
+    drop(client);
+    match server.join().unwrap() {
+      RpcError::Link(_) => {},
+      e => panic!("Expected the link to close, got {:?}\n", e),
+    }
+  }
+
+  #[test]
+  fn test_retried_call_runs_once() {
+    let addr = "rpc-retry";
+    let listener = LoopbackListener::bind(addr.to_string()).unwrap();
+    let server = thread::spawn(move || {
+      let mut server: RpcServer<Loopback> = RpcServer::accept(&listener, RetryPolicy::default()).unwrap();
+      server.register("slow", |n: u64| {
+        thread::sleep(Duration::from_millis(100));
+        n + 1
+      }).unwrap();
+      server.register("double", |n: u64| n * 2).unwrap();
+      for _ in 0..3 {
+        server.serve_one().unwrap();
+      }
+      server.nexecuted()
+    });
+    let mut client: RpcClient<Loopback> = RpcClient::connect(addr.to_string(), RetryPolicy::default()).unwrap();
+    let call = client.request("slow", &1u64).unwrap();
+    match client.wait::<u64>(call, Duration::from_millis(20)) {
+      Error(RpcError::DeadlineExceeded{call: late}) => assert_eq!(late, call),
+      _ => panic!("Expected the deadline to pass\n"),
+    }
+    client.retry(call).unwrap();
+    assert_eq!(client.wait::<u64>(call, Duration::from_secs(1)).unwrap(), 2);
+    // The answer to the retry is a duplicate and must not be taken for the next call
+    assert_eq!(client.call::<u64, u64>("double", &3, Duration::from_secs(1)).unwrap(), 6);
+    match client.wait::<u64>(call, Duration::from_millis(1)) {
+      Error(RpcError::UnknownCall{..}) => {},
+      _ => panic!("Expected the call to be answered already\n"),
+    }
+    client.flush().unwrap();
+    assert_eq!(server.join().unwrap(), 2);
+  }
+}
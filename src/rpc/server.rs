@@ -0,0 +1,128 @@
+use std::collections::{HashMap, VecDeque};
+
+use prusti_contracts::*;
+
+use crate::duplex::Duplex;
+use crate::sender::RetryPolicy;
+use crate::types::socket::Socket;
+use crate::types::transport::{Listener, Transport};
+use crate::types::MyResult::{Error, Value};
+use super::{Envelope, RpcError, Status, Wire, MAX_METHOD_LEN};
+
+type MyResult<T> = crate::types::MyResult<T, RpcError>;
+
+/// Replies the server remembers, a call retried after this many newer ones is answered Expired
+pub const REPLY_CACHE_CAPACITY: usize = 256;
+
+type Handler = Box<dyn FnMut(&[u8]) -> Option<Vec<u8>>>;
+
+/// Serving side of the RPC layer. Every call runs at most once,
+/// a retried call is answered with the reply of its first run
+pub struct RpcServer<T: Transport = Socket> {
+  link: Duplex<T>,
+  handlers: HashMap<String, Handler>,
+  // Replies of the latest calls by correlation id, oldest first in order
+  replies: HashMap<u64, (Status, Vec<u8>)>,
+  order: VecDeque<u64>,
+  // Highest correlation id answered, the client numbers its calls in increasing order
+  highest: Option<u64>,
+  nexecuted: usize,
+}
+
+impl<T: Transport> RpcServer<T> {
+  pub fn accept<L: Listener<Transport = T>>(listener: &L, policy: RetryPolicy) -> MyResult<RpcServer<T>> {
+    match Duplex::accept(listener, policy) {
+      Value(link) => Value(RpcServer::from_link(link)),
+      Error(e) => Error(RpcError::Link(e)),
+    }
+  }
+
+  pub fn from_link(link: Duplex<T>) -> Self {
+    RpcServer {
+      link,
+      handlers: HashMap::new(),
+      replies: HashMap::new(),
+      order: VecDeque::new(),
+      highest: None,
+      nexecuted: 0,
+    }
+  }
+
+  /// Answer calls of method with handler, requests that do not decode as Req are answered BadRequest.
+  /// Method names longer than MAX_METHOD_LEN bytes are refused, no client could call them
+  pub fn register<Req, Resp, F>(&mut self, method: &str, mut handler: F) -> MyResult<()>
+  where Req: Wire, Resp: Wire, F: FnMut(Req) -> Resp + 'static {
+    if method.len() > MAX_METHOD_LEN {
+      return Error(RpcError::MethodTooLong);
+    }
+    let handler = move |body: &[u8]| Req::decode(body).map(|req| handler(req).encode());
+    self.handlers.insert(method.to_string(), Box::new(handler));
+    Value(())
+  }
+
+  /// Number of handler runs so far
+  #[pure]
+  pub fn nexecuted(&self) -> usize {
+    self.nexecuted
+  }
+
+  /// Answer calls until the link fails
+  pub fn serve(&mut self) -> RpcError {
+    loop {
+      if let Error(e) = self.serve_one() {
+        return e;
+      }
+    }
+  }
+
+  /// Receive one request and answer it
+  pub fn serve_one(&mut self) -> MyResult<()> {
+    let data = match self.link.recv() {
+      Value((_, data)) => data,
+      Error(e) => return Error(RpcError::Link(e)),
+    };
+    let (call, method, body) = match Envelope::decode(&data) {
+      Some(Envelope::Request {call, method, body}) => (call, method, body),
+      _ => return Value(()),
+    };
+    let (status, body) = match self.replies.get(&call) {
+      Some(reply) => reply.clone(),
+      None if self.highest.is_some_and(|highest| call <= highest) => (Status::Expired, Vec::new()),
+      None => {
+        // A slow handler must not keep the client retransmitting the request
+        if let Error(e) = self.link.flush() {
+          return Error(RpcError::Link(e));
+        }
+        let reply = self.execute(&method, &body);
+        self.remember(call, reply.clone());
+        reply
+      },
+    };
+    match self.link.send(Envelope::Reply {call, status, body}.encode()) {
+      Value(_) => Value(()),
+      Error(e) => Error(RpcError::Link(e)),
+    }
+  }
+
+  fn execute(&mut self, method: &str, body: &[u8]) -> (Status, Vec<u8>) {
+    let handler = match self.handlers.get_mut(method) {
+      Some(handler) => handler,
+      None => return (Status::UnknownMethod, Vec::new()),
+    };
+    self.nexecuted += 1;
+    match handler(body) {
+      Some(reply) => (Status::Ok, reply),
+      None => (Status::BadRequest, Vec::new()),
+    }
+  }
+
+  fn remember(&mut self, call: u64, reply: (Status, Vec<u8>)) {
+    self.highest = Some(call);
+    self.replies.insert(call, reply);
+    self.order.push_back(call);
+    if self.order.len() > REPLY_CACHE_CAPACITY {
+      let oldest = self.order.pop_front().unwrap();
+      self.replies.remove(&oldest);
+    }
+  }
+}
@@ -0,0 +1,115 @@
+use prusti_contracts::*;
+
+/// Decides how many frames the windowed sender keeps in flight, on top of the link
+/// capacity and the receiver's window. The sender reports every ack and every loss
+pub trait CongestionControl {
+  /// Congestion window in frames, never 0
+  #[pure]
+  fn cwnd(&self) -> usize;
+
+  /// acked frames were acknowledged for the first time
+  fn on_ack(&mut self, acked: usize);
+
+  /// A retransmission timer ran out while other frames were still being acknowledged
+  fn on_loss(&mut self);
+
+  /// A retransmission timer ran out and nothing was acknowledged since the last one
+  fn on_timeout(&mut self);
+}
+
+/// Additive increase, multiplicative decrease as in TCP Reno (RFC 5681).
+/// The window grows by one frame per ack in slow start and by one frame per window
+/// in congestion avoidance, a loss halves it and a timeout sends it back to slow start
+#[derive(Clone, Debug)]
+pub struct Aimd {
+  cwnd: usize,
+  // Slow start ends once cwnd reaches it
+  ssthresh: usize,
+  // Acks counted towards the next additive increase
+  acked: usize,
+}
+
+impl Aimd {
+  #[ensures(result.cwnd() == 1)]
+  pub fn new() -> Self {
+    Aimd { cwnd: 1, ssthresh: usize::MAX, acked: 0 }
+  }
+
+  #[pure]
+  pub fn ssthresh(&self) -> usize {
+    self.ssthresh
+  }
+
+  #[pure]
+  pub fn in_slow_start(&self) -> bool {
+    self.cwnd < self.ssthresh
+  }
+}
+
+impl Default for Aimd {
+  fn default() -> Self {
+    Aimd::new()
+  }
+}
+
+impl CongestionControl for Aimd {
+  #[pure]
+  fn cwnd(&self) -> usize {
+    self.cwnd
+  }
+
+  fn on_ack(&mut self, acked: usize) {
+    for _ in 0..acked {
+      if self.in_slow_start() {
+        self.cwnd += 1;
+      } else {
+        self.acked += 1;
+        if self.acked >= self.cwnd {
+          self.acked = 0;
+          self.cwnd += 1;
+        }
+      }
+    }
+  }
+
+  fn on_loss(&mut self) {
+    self.ssthresh = (self.cwnd / 2).max(2);
+    self.cwnd = self.ssthresh;
+    self.acked = 0;
+  }
+
+  fn on_timeout(&mut self) {
+    self.ssthresh = (self.cwnd / 2).max(2);
+    self.cwnd = 1;
+    self.acked = 0;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Aimd, CongestionControl};
+
+  #[test]
+  fn test_aimd() {
+    let mut cc = Aimd::new();
+    // Slow start doubles the window every round trip
+    for round in 0..4 {
+      let cwnd = cc.cwnd();
+      cc.on_ack(cwnd);
+      assert_eq!(cc.cwnd(), 2 << round);
+    }
+    cc.on_loss();
+    assert_eq!((cc.cwnd(), cc.ssthresh()), (8, 8));
+    assert!(!cc.in_slow_start());
+    // One frame more per window
+    cc.on_ack(7);
+    assert_eq!(cc.cwnd(), 8);
+    cc.on_ack(1);
+    assert_eq!(cc.cwnd(), 9);
+    cc.on_timeout();
+    assert_eq!((cc.cwnd(), cc.ssthresh()), (1, 4));
+    cc.on_ack(3);
+    assert_eq!(cc.cwnd(), 4);
+    assert!(!cc.in_slow_start());
+  }
+}
@@ -1,233 +1,863 @@
-// use core::time;
-// use std::{task::ready, thread, time::Duration};
-
-// use self::{state::{error::Result, Ready, Pending}, types::{array::Array, MyResult::*}};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
 use super::*; 
-mod state;
-
-// pub struct Sender {
-//   remote_addr: String,
-//   // ready_state == None when ready_state is not ready to send
-//   ready_state: Option<Ready>,
-//   pending_state: Option<Pending>,
-//   sending: Option<u8>,
-//   delivered: Array<u8>,
-// }
-
-// #[derive(Clone, Debug)]
-// enum SenderError {
-//   RemoteUnavailable,
-//   Timeout,
-//   SendFailed,
-//   IllegalState
-// }
-// use SenderError::*;
-// type MyResult<T> = crate::types::MyResult<T, SenderError>;
-
-// const SLEEP_TIME_SECONDS: u64 = 1;
-// const TIMEOUT_SECONDS: u64 = 120;
-
-// pub fn init(remote_addr: String) -> Sender {
-//   Sender {
-//     remote_addr,
-//     ready_state: None,
-//     pending_state: None,
-//     sending: None,
-//     delivered: Array::new(),
-//   }
-// }
-// /// Setup the sender part of the perfect link with the remote address
-// /// This function always return an instance of Sender. Blocks until the remote is available.
-// // fn connect(remote_addr: String) -> Sender {
-// //   let res = try_connect(remote_addr, Duration::from_secs(SLEEP_TIME_SECONDS),
-// //      Duration::ZERO, Duration::from_secs(TIMEOUT_SECONDS));
-// //   match res {
-// //     MyResult::Value(sender) => sender,
-// //     MyResult::Error(e) => {
-// //       print!("Error setting up sender: {:?}", e);
-// //       panic!();
-// //     }
-// //   }
-// // }
-
-// // fn try_connect(remote_addr: String, sleep_time: Duration, time_acc: Duration, timeout: Duration) -> MyResult<Sender> {
-// //   if time_acc >= timeout {
-// //     return MyResult::Error(Timeout);
-// //   }
-// //   let res = state::connect(remote_addr);
-// //   match res {
-// //     Result::Value(ready) => MyResult::Value(Sender {remote_addr, ready_state: ready}),
-// //     Result::Error(_) => {
-// //       thread::sleep(sleep_time);
-// //       try_connect(remote_addr, sleep_time, time_acc + sleep_time, timeout)
-// //     }
-// //   }
-// // }
-
-// // fn handle_send_error(remote_addr: String) -> Sender {
-// //   print!("Handling send error unimplemented");
-// //   setup(remote_addr)
-// // }
-// impl Sender {
-//   /// Setup the sender part of the perfect link with the remote address
-//   /// This function always return an instance of Sender. Blocks until the remote is available.
-//   fn connect(self) -> Sender {
-//     if self.ready_state.is_some() {
-//       return self;
-//     }
-//     let res = self.try_connect(Duration::from_secs(SLEEP_TIME_SECONDS),
-//       Duration::ZERO, Duration::from_secs(TIMEOUT_SECONDS));
-//     match res {
-//       MyResult::Value(sender) => sender,
-//       MyResult::Error(e) => {
-//         print!("Error setting up sender: {:?}", e);
-//         panic!();
-//       }
-//     }
-//   }
-
-//   fn try_connect(self, sleep_time: Duration, time_acc: Duration, timeout: Duration) -> MyResult<Sender> {
-//     if time_acc >= timeout {
-//       return MyResult::Error(Timeout);
-//     }
-//     let res = state::connect(self.remote_addr.clone());
-//     match res {
-//       Result::Value(ready) => MyResult::Value(self.with_ready_state(ready)),
-//       Result::Error(_) => {
-//         thread::sleep(sleep_time);
-//         self.try_connect(sleep_time, time_acc + sleep_time, timeout)
-//       }
-//     }
-//   }
-
-//   fn send(mut self, data: u8) -> Sender {
-//     let s = self.connect_if_required(); // 1. Connect to remote if not connected
-//     //   .send_data(data) // 2. Send data
-//     //   .await_deliver(data); // 3. Await deliver
-
-//     // 2. Send data
-//     let res = s.send_data(data);
-//     match res {
-//         Result::Value(pending) => s.without_ready_state()
-//             .with_pending_state(pending)
-//             .await_deliver(data),
-//         Result::Error(_) => s,
-    
-//     // print!("Data delivered: {}\n", data);
-//     // s
-//     }
-//   }
-
-//   fn connect_if_required(self) -> Sender {
-//     if self.ready_state.is_none() {
-//         return self.connect();
-//     }
-//     self
-//   }
-
-//   fn send_data(mut self, data: u8) -> Result<Pending> {
-//     match self.ready_state {
-//       Some(ready) => {
-//         self.sending = Some(data);
-//         let res = ready.send(data);
-//         res
-//       },
-//       None => panic!("Illegal state")
-//     }
-//   }
-
-//   fn handle_send_error(mut self, e: SenderError) -> Sender {
-//     self.without_ready_state();
-//     panic!("Handling send error unimplemented");
-//   }
-
-//   fn await_deliver(mut self, data: u8) -> Sender {
-//     match self.pending_state {
-//       Some(pending) => {
-//         let res = pending.wait_deliver(Duration::from_secs(TIMEOUT_SECONDS));
-//         match res {
-//           (Result::Value(ready), true) => {
-//             self.delivered.push(data);
-//             self.without_sending()
-//               .with_ready_state(ready)
-//           },
-//           (Result::Value(_), false) => {
-//             self.send(data)
-//           }
-//           (Result::Error(e), _) => {
-//             self.handle_wait_error()
-//           }
-//         }
-//       },
-//       None => panic!("Is not pending")
-//     }
-//   } 
-
-//   fn handle_wait_error(mut self) -> Sender {
-//     print!("Handling wait error unimplemented");
-//     self
-//   }
-
-//   fn with_ready_state(mut self, ready: Ready) -> Sender {
-//     self.ready_state = Some(ready);
-//     self
-//   }
-
-//   fn with_pending_state(mut self, pending: Pending) -> Sender {
-//     self.pending_state = Some(pending);
-//     self
-//   }
-
-//   fn without_pending_state(mut self) -> Sender {
-//     self.pending_state = None;
-//     self
-//   }
-
-//   fn without_ready_state(mut self) -> Sender {
-//     self.ready_state = None;
-//     self
-//   }
-
-//   fn without_sending(mut self) -> Sender {
-//     self.sending = None;
-//     self
-//   }
-// }
-
-// #[cfg(test)]
-// mod tests {
-//     use std::thread;
-
-//     use crate::types::socket::{ServerSocket, Socket};
-
-//     use super::init;
-
-//     #[test]
-//     fn can_connect() {
-//         let addr = "localhost:8080";
-//         let rj = thread::spawn(move || {
-//             recevier_accept(addr.to_string().clone());
-//         });
-//         init(addr.to_string()).connect();
-//     }
-
-//     fn run_receiver(addr: String) {
-//         let mut socket = recevier_accept(addr);
-//         loop {
-//             let v = socket.recv().unwrap();
-//             print!("Received data {}\n", v);
-//             socket.send(v).unwrap();
-//             print!("Sent ACK for data {}\n", v);
-//         }
-//     }
-
-//     fn recevier_accept(addr: String) -> Socket {
-//         let s = ServerSocket::bind(addr)
-//             .unwrap()
-//             .accept()
-//             .unwrap();
-//         print!("Accepted incoming connection\n");
-//         s
-//     }
-// }
+use crate::types::array::Array;
+use crate::types::next_seq;
+use self::state::error::Result::{Value, Error};
+use self::state::Ready;
+use self::state::window::Pipeline;
+use crate::types::socket::Socket;
+use crate::types::transport::Transport;
+mod congestion;
+mod outbox;
+mod reconnect;
+mod rto;
+pub(crate) mod state;
+
+pub use self::congestion::{Aimd, CongestionControl};
+pub use self::outbox::{Outbox, COMPACT_AFTER};
+pub use self::reconnect::ReconnectPolicy;
+pub use self::rto::{RttEstimator, MAX_RTO, MIN_RTO};
+pub use self::state::SenderError;
+
+type MyResult<T> = crate::types::MyResult<T, SenderError>;
+
+/// How long the sender waits for an ack before retransmitting, and when it gives up
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+  /// Handshake timeout, and retransmission timeout until the first round trip is measured
+  pub timeout: Duration,
+  /// Transmissions of a single message before giving up, None retries forever
+  pub max_attempts: Option<usize>,
+}
+
+impl RetryPolicy {
+  /// Retransmit every timeout until the message is acknowledged
+  pub fn forever(timeout: Duration) -> Self {
+    RetryPolicy { timeout, max_attempts: None }
+  }
+
+  pub fn with_max_attempts(timeout: Duration, max_attempts: usize) -> Self {
+    RetryPolicy { timeout, max_attempts: Some(max_attempts) }
+  }
+
+  #[pure]
+  pub fn gives_up(&self, attempts: usize) -> bool {
+    match self.max_attempts {
+      Some(max) => attempts >= max,
+      None => false,
+    }
+  }
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy::forever(Duration::from_secs(1))
+  }
+}
+
+/// What send_batch retransmits when an ack is missing
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Repeat {
+  /// Every outstanding message from the oldest unacknowledged one on
+  #[default]
+  GoBackN,
+  /// Only the messages whose own ack is missing
+  Selective,
+}
+
+/// Proof that the receiver acknowledged a message
+#[derive(Clone, Debug, PartialEq)]
+pub struct Receipt {
+  pub seq: u64,
+  /// Number of transmissions it took, 1 if the first one got through
+  pub attempts: usize,
+}
+
+/// Sender side of the perfect link, drives the Connect -> Ready -> Pending typestates
+/// and keeps retransmitting every message until it is acknowledged
+pub struct PerfectLinkSender<T: Transport = Socket, C: CongestionControl = Aimd> {
+  remote_addr: String,
+  policy: RetryPolicy,
+  reconnect_policy: ReconnectPolicy,
+  // Messages outstanding at once in send_batch
+  window: usize,
+  repeat: Repeat,
+  rtt: RttEstimator,
+  congestion: C,
+  // None when no link is established
+  ready_state: Option<Ready<T>>,
+  delivered: Array<u64>,
+  // Crash-recovery mode, see with_outbox
+  outbox: Option<Outbox>,
+  // Initial sequence number of the link and the seq it goes on at,
+  // kept when the link is torn down so the next connect resumes it
+  session: Option<(u64, u64)>,
+  // Unacknowledged messages of a logged link the receiver refused to resume, see take_lost
+  lost: Vec<(u64, Vec<u8>)>,
+}
+
+impl<T: Transport> PerfectLinkSender<T> {
+  /// The link is only established by the first send
+  pub fn new(remote_addr: String, policy: RetryPolicy) -> Self {
+    let rtt = RttEstimator::new(policy.timeout);
+    PerfectLinkSender { remote_addr, policy, reconnect_policy: ReconnectPolicy::default(), window: 1, repeat: Repeat::default(), rtt, congestion: Aimd::new(), ready_state: None, delivered: Array::new(), outbox: None, session: None, lost: Vec::new() }
+  }
+
+  /// Sender towards link.dst that keeps up to link.capacity messages in flight
+  #[requires(link.capacity > 0)]
+  pub fn from_link(link: &Link, policy: RetryPolicy) -> Self {
+    let rtt = RttEstimator::new(policy.timeout);
+    PerfectLinkSender { remote_addr: link.dst.clone(), policy, reconnect_policy: ReconnectPolicy::default(), window: link.capacity, repeat: Repeat::default(), rtt, congestion: Aimd::new(), ready_state: None, delivered: Array::new(), outbox: None, session: None, lost: Vec::new() }
+  }
+}
+
+impl<T: Transport, C: CongestionControl> PerfectLinkSender<T, C> {
+  /// Replace the congestion control of send_batch, AIMD by default
+  pub fn with_congestion<D: CongestionControl>(self, congestion: D) -> PerfectLinkSender<T, D> {
+    PerfectLinkSender {
+      remote_addr: self.remote_addr,
+      policy: self.policy,
+      reconnect_policy: self.reconnect_policy,
+      window: self.window,
+      repeat: self.repeat,
+      rtt: self.rtt,
+      congestion,
+      ready_state: self.ready_state,
+      delivered: self.delivered,
+      outbox: self.outbox,
+      session: self.session,
+      lost: self.lost,
+    }
+  }
+
+  /// Use the given retransmission scheme in send_batch
+  pub fn with_repeat(mut self, repeat: Repeat) -> Self {
+    self.repeat = repeat;
+    self
+  }
+
+  /// Keep trying to open the transport as chosen by policy while the receiver is unreachable,
+  /// when the link is first established and whenever it is resumed. A single attempt by default
+  pub fn with_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+    self.reconnect_policy = policy;
+    self
+  }
+
+  /// Crash-recovery mode: every message is logged to outbox before it is sent and removed once
+  /// acknowledged. A sender restarted with the same outbox resumes the logged link and replays
+  /// the unacknowledged messages under their original seqs before sending anything new
+  pub fn with_outbox(mut self, outbox: Outbox) -> Self {
+    self.outbox = Some(outbox);
+    self
+  }
+
+  pub fn outbox(&self) -> Option<&Outbox> {
+    self.outbox.as_ref()
+  }
+
+  #[pure]
+  pub fn window(&self) -> usize {
+    self.window
+  }
+
+  pub fn congestion(&self) -> &C {
+    &self.congestion
+  }
+
+  /// Round trip estimates and the current retransmission timeout
+  pub fn rtt(&self) -> &RttEstimator {
+    &self.rtt
+  }
+
+  /// Initial sequence number of the current link, the receiver knows the link by it
+  /// when it is resumed over a new connection
+  pub fn session(&self) -> Option<u64> {
+    self.session.map(|(isn, _)| isn)
+  }
+
+  #[pure]
+  pub fn is_connected(&self) -> bool {
+    self.ready_state.is_some()
+  }
+
+  /// Number of messages acknowledged by the receiver
+  #[pure]
+  pub fn ndelivered(&self) -> usize {
+    self.delivered.len()
+  }
+
+  /// Establish the link if required, opening the transport follows the reconnect policy
+  /// and the handshake the retry policy.
+  /// A link torn down earlier is resumed and goes on with the seq after the last one it sent.
+  /// With an outbox, the logged link is resumed at its oldest unacknowledged message and those
+  /// messages are replayed. When the receiver refuses to resume the link a fresh one is established,
+  /// the unacknowledged messages of the outbox are then handed to take_lost instead
+  pub fn connect(&mut self) -> MyResult<()> {
+    if self.ready_state.is_some() {
+      return MyResult::Value(());
+    }
+    let logged = self.outbox.as_ref().and_then(|outbox| {
+      let first = outbox.unacked().first().map_or(outbox.next(), |(seq, _)| *seq);
+      outbox.isn().map(|isn| (isn, first))
+    });
+    self.establish(logged.or(self.session))
+  }
+
+  /// Messages of a logged link the receiver refused to resume, as (seq, data) in send order.
+  /// They were never acknowledged and may or may not have been delivered, sending them again
+  /// is up to the application
+  pub fn take_lost(&mut self) -> Vec<(u64, Vec<u8>)> {
+    std::mem::take(&mut self.lost)
+  }
+
+  /// Resume the link (isn, seq), or establish a fresh one if there is none or it was refused
+  fn establish(&mut self, resumed: Option<(u64, u64)>) -> MyResult<()> {
+    let attempts = self.policy.max_attempts.unwrap_or(usize::MAX);
+    let remote_addr = self.remote_addr.clone();
+    let connect = self.reconnect_policy.run(|| match resumed {
+      Some((isn, seq)) => state::resume(remote_addr.clone(), isn, seq),
+      None => state::connect(remote_addr.clone()),
+    });
+    let res = match connect {
+      Value(connect) => {
+        let isn = connect.seq();
+        match connect.handshake(self.policy.timeout, attempts) {
+          Value(ready) => Value((isn, ready)),
+          Error(e) => Error(e),
+        }
+      },
+      Error(e) => Error(e),
+    };
+    let (isn, ready) = match res {
+      Value(res) => res,
+      // The receiver forgot the link or cannot tell what it delivered
+      Error(SenderError::Refused) if resumed.is_some() => {
+        self.session = None;
+        if let Some(outbox) = self.outbox.as_ref() {
+          self.lost.extend(outbox.unacked().iter().cloned());
+        }
+        return self.establish(None);
+      },
+      Error(e) => return MyResult::Error(e),
+    };
+    let ready = match self.outbox.as_mut() {
+      Some(outbox) if resumed.is_some() => {
+        let next = outbox.next();
+        match self.replay(ready) {
+          Value(ready) => ready.skip_to(next),
+          Error(e) => return MyResult::Error(e),
+        }
+      },
+      // The outbox starts over, its messages were reported lost
+      Some(outbox) => match outbox.begin(isn) {
+        MyResult::Value(_) => ready,
+        MyResult::Error(e) => return MyResult::Error(e),
+      },
+      None => ready,
+    };
+    self.session = Some((isn, ready.seq()));
+    self.ready_state = Some(ready);
+    MyResult::Value(())
+  }
+
+  /// Resume the current link over a new connection and go on at seq
+  fn reconnect(&mut self, seq: u64) -> state::error::Result<Ready<T>> {
+    let isn = match self.session {
+      Some((isn, _)) => isn,
+      None => return Error(SenderError::IllegalState),
+    };
+    let attempts = self.policy.max_attempts.unwrap_or(usize::MAX);
+    match self.reconnect_policy.run(|| state::resume(self.remote_addr.clone(), isn, seq)) {
+      Value(connect) => connect.handshake(self.policy.timeout, attempts),
+      Error(e) => Error(e),
+    }
+  }
+
+  /// Send the logged messages that were never acknowledged again, under their original seqs.
+  /// The receiver acknowledges the ones it already delivered without delivering them twice
+  fn replay(&mut self, mut ready: Ready<T>) -> state::error::Result<Ready<T>> {
+    let unacked = match self.outbox.as_ref() {
+      Some(outbox) => outbox.unacked().to_vec(),
+      None => Vec::new(),
+    };
+    for (seq, data) in unacked {
+      ready = match self.transmit(ready.skip_to(seq), data) {
+        Value((ready, _)) => ready,
+        Error(e) => return Error(e),
+      };
+      if let Some(outbox) = self.outbox.as_mut() {
+        if let MyResult::Error(e) = outbox.log_ack(seq) {
+          return Error(e);
+        }
+      }
+    }
+    Value(ready)
+  }
+
+  /// Send data and retransmit it under the same seq until it is acknowledged,
+  /// waiting for the adaptive retransmission timeout after each transmission.
+  /// Nothing is sent while the receiver's window is closed, see probe.
+  /// When the transport fails, the link is resumed over a new connection and data is
+  /// retransmitted under the same seq, so the receiver still delivers it at most once.
+  /// When the retry policy gives up, or the link cannot be resumed, the link is torn down
+  /// and the next send goes on after this seq
+  #[ensures(result.is_ok() ==> self.ndelivered() == old(self.ndelivered()) + 1)]
+  #[ensures(!result.is_ok() ==> !self.is_connected())]
+  pub fn send(&mut self, data: Vec<u8>) -> MyResult<Receipt> {
+    let res = self.connect();
+    if res.is_err() {
+      return MyResult::Error(res.unwrap_err());
+    }
+    let ready = match self.ready_state.take() {
+      Some(ready) => ready,
+      None => return MyResult::Error(SenderError::IllegalState),
+    };
+    let seq = ready.seq();
+    if let Some(outbox) = self.outbox.as_mut() {
+      if let MyResult::Error(e) = outbox.log_send(seq, data.clone()) {
+        return MyResult::Error(e);
+      }
+    }
+    // Whatever happens to this message, the link goes on after it
+    self.session = self.session.map(|(isn, _)| (isn, next_seq(seq)));
+    let mut ready = ready;
+    let mut reconnects = 0;
+    let (next, receipt) = loop {
+      match self.transmit(ready, data.clone()) {
+        Value(res) => break res,
+        Error(SenderError::SendError{..}) | Error(SenderError::NoResponse) if !self.policy.gives_up(reconnects) => {
+          reconnects += 1;
+          ready = match self.reconnect(seq) {
+            Value(ready) => ready,
+            Error(e) => return MyResult::Error(e),
+          };
+        },
+        Error(e) => return MyResult::Error(e),
+      }
+    };
+    if let Some(outbox) = self.outbox.as_mut() {
+      if let MyResult::Error(e) = outbox.log_ack(seq) {
+        return MyResult::Error(e);
+      }
+    }
+    if self.delivered.push(seq).is_err() {
+      return MyResult::Error(SenderError::IllegalState);
+    }
+    self.ready_state = Some(next);
+    MyResult::Value(receipt)
+  }
+
+  /// Stop-and-wait transmission of data under the seq of ready, until it is acknowledged
+  /// or the retry policy gives up
+  fn transmit(&mut self, mut ready: Ready<T>, data: Vec<u8>) -> state::error::Result<(Ready<T>, Receipt)> {
+    if ready.rwnd() == 0 {
+      ready = match self.probe(ready) {
+        Value(ready) => ready,
+        Error(e) => return Error(e),
+      };
+    }
+    let mut attempts = 0;
+    loop {
+      attempts += 1;
+      let seq = ready.seq();
+      let sent = Instant::now();
+      let pending = match ready.send(data.clone()) {
+        Value(pending) => pending,
+        Error(e) => return Error(e),
+      };
+      match pending.wait_deliver(self.rtt.rto()) {
+        (Value(next), true) => {
+          if attempts == 1 {
+            self.rtt.sample(sent.elapsed());
+          } else {
+            self.rtt.reset_backoff();
+          }
+          return Value((next, Receipt { seq, attempts }));
+        },
+        (Value(same), false) => {
+          if self.policy.gives_up(attempts) {
+            // The seq may still be delivered later, so it must not be reused for other data
+            return Error(SenderError::Timeout);
+          }
+          self.rtt.backoff();
+          ready = same;
+        },
+        (Error(e), _) => return Error(e),
+      }
+    }
+  }
+
+  /// Probe the closed receiver window until it reopens, backing off like retransmissions.
+  /// The retry policy bounds the number of unanswered probes
+  fn probe(&mut self, mut ready: Ready<T>) -> state::error::Result<Ready<T>> {
+    let mut probes = 0;
+    while ready.rwnd() == 0 {
+      if self.policy.gives_up(probes) {
+        return Error(SenderError::Timeout);
+      }
+      ready = match ready.probe(self.rtt.rto()) {
+        Value(ready) => ready,
+        Error(e) => return Error(e),
+      };
+      probes += 1;
+      self.rtt.backoff();
+    }
+    self.rtt.reset_backoff();
+    Value(ready)
+  }
+
+  /// Send every message with up to window() of them in flight, and never more than the
+  /// congestion window or the receiver's window, retransmitting as chosen with with_repeat. Receipts come back in send order.
+  /// When the transport fails, the link is resumed over a new connection like in send and the
+  /// messages still in flight are sent again under their seqs.
+  /// The retry policy applies to every message, to window probes and to reconnects, when it gives up
+  /// the link is torn down like in send
+  #[ensures(result.is_ok() ==> self.ndelivered() == old(self.ndelivered()) + data.len())]
+  #[ensures(!result.is_ok() ==> !self.is_connected())]
+  pub fn send_batch(&mut self, data: Vec<Vec<u8>>) -> MyResult<Vec<Receipt>> {
+    let res = self.connect();
+    if res.is_err() {
+      return MyResult::Error(res.unwrap_err());
+    }
+    let ready = match self.ready_state.take() {
+      Some(ready) => ready,
+      None => return MyResult::Error(SenderError::IllegalState),
+    };
+    let capacity = self.window;
+    match self.repeat {
+      Repeat::GoBackN => self.pipeline(|ready| ready.into_window(capacity), ready, data),
+      Repeat::Selective => self.pipeline(|ready| ready.into_selective(capacity), ready, data),
+    }
+  }
+
+  fn pipeline<P: Pipeline<T>, F: Fn(Ready<T>) -> P>(&mut self, open: F, ready: Ready<T>, data: Vec<Vec<u8>>) -> MyResult<Vec<Receipt>> {
+    let total = data.len();
+    let mut data = data.into_iter();
+    let mut receipts = Vec::with_capacity(total);
+    // Sent and not acknowledged yet, and what to send again after a reconnect, as (seq, data)
+    let mut inflight = VecDeque::new();
+    let mut resend = VecDeque::new();
+    let mut window = open(ready);
+    let mut reconnects = 0;
+    loop {
+      match self.slide(&mut window, &mut data, &mut inflight, &mut resend, &mut receipts) {
+        Value(()) => break,
+        Error(SenderError::SendError{..}) | Error(SenderError::NoResponse) if !self.policy.gives_up(reconnects) => {
+          reconnects += 1;
+          let mut unacked = std::mem::take(&mut inflight);
+          unacked.append(&mut resend);
+          resend = unacked;
+          let seq = resend.front().map_or(window.seq(), |(seq, _)| *seq);
+          window = match self.reconnect(seq) {
+            Value(ready) => open(ready),
+            Error(e) => return MyResult::Error(e),
+          };
+        },
+        Error(e) => return MyResult::Error(e),
+      }
+    }
+    self.ready_state = Some(window.into_ready());
+    MyResult::Value(receipts)
+  }
+
+  /// Run the window until receipts holds every message of the batch, sending what is left in
+  /// resend before the rest of data. Stops at the first error, inflight then holds what was not acknowledged
+  fn slide<P: Pipeline<T>>(&mut self, window: &mut P, data: &mut std::vec::IntoIter<Vec<u8>>, inflight: &mut VecDeque<(u64, Vec<u8>)>,
+    resend: &mut VecDeque<(u64, Vec<u8>)>, receipts: &mut Vec<Receipt>) -> state::error::Result<()> {
+    let total = receipts.len() + inflight.len() + resend.len() + data.len();
+    let mut probes = 0;
+    // Whether anything was acknowledged since the last retransmission, a timeout before the
+    // first ack of the batch is not a loss among acked frames
+    let mut progress = false;
+    while receipts.len() < total {
+      window.set_cwnd(self.congestion.cwnd());
+      while window.can_send() {
+        let seq = window.seq();
+        let next = match resend.pop_front() {
+          Some((_, next)) => next,
+          None => match data.next() {
+            Some(next) => {
+              if let Some(outbox) = self.outbox.as_mut() {
+                if let MyResult::Error(e) = outbox.log_send(seq, next.clone()) {
+                  return Error(e);
+                }
+              }
+              next
+            },
+            None => break,
+          },
+        };
+        // Also on failure, the message is sent again once the link is resumed
+        inflight.push_back((seq, next.clone()));
+        if let Error(e) = window.send(next) {
+          return Error(e);
+        }
+        self.session = self.session.map(|(isn, _)| (isn, window.seq()));
+      }
+      // Zero window and nothing in flight, no ack will come unless asked for
+      let probing = window.noutstanding() == 0 && window.rwnd() == 0;
+      if probing {
+        if self.policy.gives_up(probes) {
+          return Error(SenderError::Timeout);
+        }
+        if let Error(e) = window.probe() {
+          return Error(e);
+        }
+        probes += 1;
+      }
+      // Only a window that was filled says whether a larger one would be used (RFC 7661)
+      let cwnd_limited = window.noutstanding() >= self.congestion.cwnd();
+      let acked = match window.wait_acks(self.rtt.rto()) {
+        Value(acked) => acked,
+        Error(e) => return Error(e),
+      };
+      if probing && window.rwnd() == 0 {
+        self.rtt.backoff();
+      } else if !acked.is_empty() || probing {
+        probes = 0;
+        self.rtt.reset_backoff();
+      }
+      if !acked.is_empty() {
+        progress = true;
+        if cwnd_limited {
+          self.congestion.on_ack(acked.len());
+        }
+      }
+      for a in acked {
+        if let Some(rtt) = a.rtt {
+          self.rtt.sample(rtt);
+        }
+        if let Some(outbox) = self.outbox.as_mut() {
+          if let MyResult::Error(e) = outbox.log_ack(a.seq) {
+            return Error(e);
+          }
+        }
+        if self.delivered.push(a.seq).is_err() {
+          return Error(SenderError::IllegalState);
+        }
+        inflight.retain(|(seq, _)| *seq != a.seq);
+        receipts.push(Receipt { seq: a.seq, attempts: a.attempts });
+      }
+      let rto = self.rtt.rto();
+      let expired = window.expired_attempts(rto);
+      if self.policy.gives_up(expired) {
+        return Error(SenderError::Timeout);
+      }
+      if expired > 0 {
+        if let Error(e) = window.retransmit_expired(rto) {
+          return Error(e);
+        }
+        self.rtt.backoff();
+        if progress {
+          self.congestion.on_loss();
+        } else {
+          self.congestion.on_timeout();
+        }
+        progress = false;
+      }
+    }
+    Value(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, sync::mpsc, thread, time::Duration};
+
+  use crate::messaging::Message;
+  use crate::receiver::PerfectLinkReceiver;
+  use crate::types::loopback::{Loopback, LoopbackListener};
+  use crate::types::transport::{Listener, Transport};
+  use crate::types::{next_seq, MyResult};
+
+  use crate::Link;
+
+  use super::{CongestionControl, Outbox, PerfectLinkSender, ReconnectPolicy, RetryPolicy, SenderError};
+
+  #[test]
+  fn test_reconnect_resumes_session() {
+    let addr = "sender-reconnect";
+    let listener = LoopbackListener::bind(addr.to_string()).unwrap();
+    let rj = thread::spawn(move || {
+      let mut socket = listener.accept().unwrap();
+      let isn = match socket.recv_frame() {
+        MyResult::Value(Message::Connect {id}) => id,
+        _ => panic!("Expected handshake\n")
+      };
+      socket.send_frame(Message::Ack {id: isn, window: 1}).unwrap();
+      let first = socket.recv_frame().unwrap();
+      socket.send_frame(Message::Ack {id: first.id(), window: 1}).unwrap();
+      // The connection drops before the second message is acknowledged
+      let second = socket.recv_frame().unwrap();
+      drop(socket);
+      let mut socket = listener.accept().unwrap();
+      assert_eq!(socket.recv_frame().unwrap(), Message::Resume {id: isn, seq: second.id()});
+      socket.send_frame(Message::Ack {id: isn, window: 1}).unwrap();
+      assert_eq!(socket.recv_frame().unwrap(), second);
+      socket.send_frame(Message::Ack {id: second.id(), window: 1}).unwrap();
+      isn
+    });
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), RetryPolicy::forever(Duration::from_millis(500)));
+    let first = sender.send(vec![1]).unwrap();
+    let second = sender.send(vec![2]).unwrap();
+    assert_eq!(second.seq, next_seq(first.seq));
+    assert_eq!(sender.session(), Some(rj.join().unwrap()));
+  }
+
+  #[test]
+  fn test_batch_resumes_session() {
+    let addr = "sender-batch-reconnect";
+    let listener = LoopbackListener::bind(addr.to_string()).unwrap();
+    let rj = thread::spawn(move || {
+      let mut socket = listener.accept().unwrap();
+      let isn = socket.recv_frame().unwrap().id();
+      socket.send_frame(Message::Ack {id: isn, window: 4}).unwrap();
+      let first = socket.recv_frame().unwrap();
+      socket.send_frame(Message::Ack {id: first.id(), window: 4}).unwrap();
+      // The connection drops while the second and third messages are in flight
+      let second = socket.recv_frame().unwrap();
+      let third = socket.recv_frame().unwrap();
+      drop(socket);
+      let mut socket = listener.accept().unwrap();
+      assert_eq!(socket.recv_frame().unwrap(), Message::Resume {id: isn, seq: second.id()});
+      socket.send_frame(Message::Ack {id: isn, window: 4}).unwrap();
+      for msg in [second, third] {
+        assert_eq!(socket.recv_frame().unwrap(), msg);
+        socket.send_frame(Message::Ack {id: msg.id(), window: 4}).unwrap();
+      }
+      isn
+    });
+    let link = Link { src: "batch-reconnect-src".to_string(), dst: addr.to_string(), capacity: 4 };
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::from_link(&link, RetryPolicy::forever(Duration::from_millis(500)));
+    let receipts = sender.send_batch(vec![vec![1], vec![2], vec![3]]).unwrap();
+    assert_eq!(receipts.len(), 3);
+    assert_eq!(receipts[2].seq, receipts[0].seq.wrapping_add(2));
+    assert_eq!(sender.ndelivered(), 3);
+    assert_eq!(sender.session(), Some(rj.join().unwrap()));
+  }
+
+  #[test]
+  fn test_refused_session_starts_over() {
+    let addr = "sender-refused";
+    let listener = LoopbackListener::bind(addr.to_string()).unwrap();
+    let rj = thread::spawn(move || {
+      let mut socket = listener.accept().unwrap();
+      let isn = socket.recv_frame().unwrap().id();
+      socket.send_frame(Message::Ack {id: isn, window: 1}).unwrap();
+      let first = socket.recv_frame().unwrap();
+      socket.send_frame(Message::Ack {id: first.id(), window: 1}).unwrap();
+      drop(socket);
+      // The receiver restarted without its state, it refuses the link until the sender starts over
+      // First while the second message is sent, then by the next send, which goes on after it
+      for seq in [next_seq(first.id()), first.id().wrapping_add(2)] {
+        let mut socket = listener.accept().unwrap();
+        assert_eq!(socket.recv_frame().unwrap(), Message::Resume {id: isn, seq});
+        socket.send_frame(Message::Refuse {id: isn}).unwrap();
+      }
+      let mut socket = listener.accept().unwrap();
+      let fresh = match socket.recv_frame().unwrap() {
+        Message::Connect {id} => id,
+        msg => panic!("Expected a fresh link, got {:?}\n", msg),
+      };
+      socket.send_frame(Message::Ack {id: fresh, window: 1}).unwrap();
+      assert_eq!(socket.recv_frame().unwrap(), Message::Data {id: next_seq(fresh), data: vec![3]});
+      socket.send_frame(Message::Ack {id: next_seq(fresh), window: 1}).unwrap();
+      fresh
+    });
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), RetryPolicy::forever(Duration::from_millis(500)));
+    sender.send(vec![1]).unwrap();
+    let isn = sender.session();
+    match sender.send(vec![2]) {
+      MyResult::Error(SenderError::Refused) => {},
+      _ => panic!("Expected the link to be refused\n"),
+    }
+    sender.send(vec![3]).unwrap();
+    let fresh = rj.join().unwrap();
+    assert!(sender.session() == Some(fresh) && isn != Some(fresh));
+  }
+
+  #[test]
+  fn test_waits_for_receiver_to_come_up() {
+    let addr = "sender-late-receiver";
+    let policy = RetryPolicy::forever(Duration::from_millis(500));
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), policy.clone());
+    match sender.send(vec![1]) {
+      MyResult::Error(SenderError::SocketError) => {},
+      _ => panic!("Expected the receiver to be unreachable\n"),
+    }
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+      thread::sleep(Duration::from_millis(50));
+      let mut receiver: PerfectLinkReceiver<LoopbackListener> = PerfectLinkReceiver::bind(addr.to_string()).unwrap();
+      receiver.serve(&mut |_, data: &[u8]| tx.send(data.to_vec()).unwrap());
+    });
+    let reconnect = ReconnectPolicy::exponential(Duration::from_millis(5), Duration::from_millis(20), Some(Duration::from_secs(5)));
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), policy).with_reconnect(reconnect);
+    sender.send(vec![2]).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec![2]);
+  }
+
+  #[test]
+  fn test_retransmits_until_acked() {
+    let addr = "sender-retransmit";
+    let listener = LoopbackListener::bind(addr.to_string()).unwrap();
+    let rj = thread::spawn(move || {
+      let mut socket = listener.accept().unwrap();
+      match socket.recv_frame() {
+        MyResult::Value(Message::Connect {id}) => socket.send_frame(Message::Ack {id, window: 1}).unwrap(),
+        _ => panic!("Expected handshake\n")
+      };
+      // Drop the first transmission, ack the retransmission
+      let first = socket.recv_frame().unwrap();
+      let second = socket.recv_frame().unwrap();
+      assert_eq!(first, second);
+      socket.send_frame(Message::Ack {id: second.id(), window: 1}).unwrap();
+    });
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), RetryPolicy::forever(Duration::from_millis(20)));
+    let receipt = sender.send(vec![1, 2, 3]).unwrap();
+    assert_eq!(receipt.attempts, 2);
+    assert_eq!(sender.ndelivered(), 1);
+    // The ack may answer either transmission, so it is not sampled
+    assert_eq!(sender.rtt().srtt(), None);
+    assert_eq!(sender.rtt().rto(), Duration::from_millis(20));
+    rj.join().unwrap();
+  }
+
+  #[test]
+  fn test_gives_up() {
+    let addr = "sender-gives-up";
+    let listener = LoopbackListener::bind(addr.to_string()).unwrap();
+    let rj = thread::spawn(move || {
+      let mut socket = listener.accept().unwrap();
+      match socket.recv_frame() {
+        MyResult::Value(Message::Connect {id}) => socket.send_frame(Message::Ack {id, window: 1}).unwrap(),
+        _ => panic!("Expected handshake\n")
+      };
+      for _ in 0..3 {
+        socket.recv_frame().unwrap();
+      }
+    });
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), RetryPolicy::with_max_attempts(Duration::from_millis(10), 3));
+    assert!(sender.send(vec![1]).is_err());
+    assert!(!sender.is_connected());
+    rj.join().unwrap();
+  }
+
+  #[test]
+  fn test_rto_adapts_to_rtt() {
+    let addr = "sender-adaptive-rto";
+    let mut receiver: PerfectLinkReceiver<LoopbackListener> = PerfectLinkReceiver::bind(addr.to_string()).unwrap();
+    thread::spawn(move || {
+      receiver.run(|_, _| {}, |_| {});
+    });
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), RetryPolicy::forever(Duration::from_secs(5)));
+    for i in 0..20u8 {
+      sender.send(vec![i]).unwrap();
+    }
+    assert!(sender.rtt().srtt().unwrap() < Duration::from_millis(100));
+    assert!(sender.rtt().rto() < Duration::from_secs(1));
+    assert_eq!(sender.rtt().backoffs(), 0);
+  }
+
+  /// Never more than one frame in flight
+  struct StopAndWait;
+
+  impl CongestionControl for StopAndWait {
+    fn cwnd(&self) -> usize {
+      1
+    }
+
+    fn on_ack(&mut self, _acked: usize) {}
+
+    fn on_loss(&mut self) {}
+
+    fn on_timeout(&mut self) {}
+  }
+
+  #[test]
+  fn test_congestion_window() {
+    let addr = "sender-congestion";
+    let mut receiver: PerfectLinkReceiver<LoopbackListener> = PerfectLinkReceiver::bind(addr.to_string()).unwrap();
+    thread::spawn(move || {
+      receiver.run(|_, _| {}, |_| {});
+    });
+    let link = Link { src: "congestion-src".to_string(), dst: addr.to_string(), capacity: 16 };
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::from_link(&link, RetryPolicy::forever(Duration::from_secs(1)));
+    assert_eq!(sender.congestion().cwnd(), 1);
+    sender.send_batch((0..100u8).map(|i| vec![i]).collect()).unwrap();
+    // Slow start, one more frame per ack until the link capacity rather than cwnd bounds the frames in flight
+    let cwnd = sender.congestion().cwnd();
+    assert!(cwnd > 16 && cwnd <= 32);
+    sender.send_batch((0..100u8).map(|i| vec![i]).collect()).unwrap();
+    assert_eq!(sender.congestion().cwnd(), cwnd);
+    let mut sender = sender.with_congestion(StopAndWait);
+    let receipts = sender.send_batch((0..5u8).map(|i| vec![i]).collect()).unwrap();
+    assert!(receipts.iter().all(|r| r.attempts == 1));
+    assert_eq!(sender.ndelivered(), 205);
+  }
+
+  #[test]
+  fn test_outbox_replays_after_restart() {
+    let addr = "sender-outbox";
+    let path = std::env::temp_dir().join(format!("plink-sender-outbox-{}.log", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let (tx, rx) = mpsc::channel();
+    let mut receiver: PerfectLinkReceiver<LoopbackListener> = PerfectLinkReceiver::bind(addr.to_string()).unwrap();
+    thread::spawn(move || {
+      receiver.run(|_, data| tx.send(data.to_vec()).unwrap(), |_| {});
+    });
+    let policy = RetryPolicy::forever(Duration::from_millis(500));
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), policy.clone())
+      .with_outbox(Outbox::open(&path).unwrap());
+    let first = sender.send(vec![0]).unwrap().seq;
+    sender.send(vec![1]).unwrap();
+    assert_eq!(sender.outbox().unwrap().nunacked(), 0);
+    drop(sender);
+    // As if the process died before logging the ack of the second message,
+    // and again right after logging a third one
+    let mut outbox = Outbox::open(&path).unwrap();
+    outbox.log_send(first.wrapping_add(1), vec![1]).unwrap();
+    outbox.log_send(first.wrapping_add(2), vec![2]).unwrap();
+    drop(outbox);
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), policy)
+      .with_outbox(Outbox::open(&path).unwrap());
+    assert_eq!(sender.send(vec![3]).unwrap().seq, first.wrapping_add(3));
+    assert_eq!(sender.outbox().unwrap().nunacked(), 0);
+    let timeout = Duration::from_secs(5);
+    for i in 0..4u8 {
+      assert_eq!(rx.recv_timeout(timeout).unwrap(), vec![i]);
+    }
+    // The replayed second message was recognized as delivered
+    assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_forgotten_outbox_link_starts_over() {
+    let addr = "sender-outbox-forgotten";
+    let path = std::env::temp_dir().join(format!("plink-sender-forgotten-{}.log", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut outbox = Outbox::open(&path).unwrap();
+    outbox.begin(77).unwrap();
+    outbox.log_send(78, vec![7]).unwrap();
+    drop(outbox);
+    // The receiver never saw link 77, e.g. it lost its state
+    let (tx, rx) = mpsc::channel();
+    let mut receiver: PerfectLinkReceiver<LoopbackListener> = PerfectLinkReceiver::bind(addr.to_string()).unwrap();
+    thread::spawn(move || {
+      receiver.run(|_, data| tx.send(data.to_vec()).unwrap(), |_| {});
+    });
+    let mut sender: PerfectLinkSender<Loopback> = PerfectLinkSender::new(addr.to_string(), RetryPolicy::forever(Duration::from_millis(500)))
+      .with_outbox(Outbox::open(&path).unwrap());
+    sender.send(vec![8]).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec![8]);
+    assert_eq!(sender.take_lost(), vec![(78, vec![7])]);
+    assert!(sender.take_lost().is_empty());
+    let outbox = sender.outbox().unwrap();
+    assert!(outbox.isn() != Some(77) && outbox.nunacked() == 0);
+    assert_eq!(outbox.isn(), sender.session());
+    fs::remove_file(&path).unwrap();
+  }
+}
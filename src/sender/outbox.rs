@@ -0,0 +1,240 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use prusti_contracts::*;
+
+use crate::types::next_seq;
+use crate::types::storage::write_atomically;
+use crate::types::MyResult::{Error, Value};
+use super::SenderError;
+
+type MyResult<T> = crate::types::MyResult<T, SenderError>;
+
+/// Records appended to the log, a full rewrite replaces them once this many accumulated
+pub const COMPACT_AFTER: usize = 1024;
+
+// Record layout: [kind: u8][seq: u64 big endian][len: u32 big endian][data...]
+const RECORD_HEADER_SIZE: usize = 1 + 8 + 4;
+// Starts a link, seq is its initial sequence number
+const LINK_RECORD: u8 = 0x0;
+// Written before the message is sent
+const SEND_RECORD: u8 = 0x1;
+// Written once the message is acknowledged
+const ACK_RECORD: u8 = 0x2;
+// Seq of the next new message, kept by rewrites when no message is left to tell it
+const NEXT_RECORD: u8 = 0x3;
+
+/// Durable log of a crash-recovery sender: the initial sequence number of its link, the next seq,
+/// and every message sent but not acknowledged. Each record is fsync'd before the call returns,
+/// so a restarted sender replays exactly the messages whose ack it never saw, under their original seqs
+pub struct Outbox {
+  path: PathBuf,
+  log: File,
+  isn: Option<u64>,
+  // Seq of the next new message
+  next: u64,
+  // In send order
+  unacked: Vec<(u64, Vec<u8>)>,
+  records: usize,
+}
+
+impl Outbox {
+  /// Open the log at path, creating it if needed, and recover what it holds.
+  /// A record torn by a crash in the middle of an append is discarded
+  pub fn open<P: AsRef<Path>>(path: P) -> MyResult<Outbox> {
+    let path = path.as_ref().to_path_buf();
+    let mut buf = Vec::new();
+    if path.exists() {
+      let read = File::open(&path).and_then(|mut file| file.read_to_end(&mut buf));
+      if read.is_err() {
+        return Error(SenderError::StorageError);
+      }
+    }
+    let log = match OpenOptions::new().create(true).append(true).open(&path) {
+      Ok(log) => log,
+      Err(_) => return Error(SenderError::StorageError),
+    };
+    let mut outbox = Outbox { path, log, isn: None, next: 0, unacked: Vec::new(), records: 0 };
+    let mut pos = 0;
+    while buf.len() - pos >= RECORD_HEADER_SIZE {
+      let kind = buf[pos];
+      let seq = u64::from_be_bytes(buf[pos + 1..pos + 9].try_into().unwrap());
+      let len = u32::from_be_bytes(buf[pos + 9..pos + RECORD_HEADER_SIZE].try_into().unwrap()) as usize;
+      let end = pos + RECORD_HEADER_SIZE + len;
+      if end > buf.len() {
+        break;
+      }
+      outbox.apply(kind, seq, buf[pos + RECORD_HEADER_SIZE..end].to_vec());
+      outbox.records += 1;
+      pos = end;
+    }
+    if pos < buf.len() && outbox.log.set_len(pos as u64).is_err() {
+      return Error(SenderError::StorageError);
+    }
+    Value(outbox)
+  }
+
+  /// Initial sequence number of the logged link, None before the first one starts
+  #[pure]
+  pub fn isn(&self) -> Option<u64> {
+    self.isn
+  }
+
+  /// Seq the next new message will be sent with
+  #[pure]
+  pub fn next(&self) -> u64 {
+    self.next
+  }
+
+  #[pure]
+  #[trusted]
+  pub fn nunacked(&self) -> usize {
+    self.unacked.len()
+  }
+
+  /// Messages to replay on restart as (seq, data), in send order
+  pub fn unacked(&self) -> &[(u64, Vec<u8>)] {
+    &self.unacked
+  }
+
+  /// Start logging a new link, whatever was logged for the previous one is dropped
+  #[ensures(result.is_ok() ==> self.isn() == Some(isn))]
+  #[ensures(result.is_ok() ==> self.next() == next_seq(isn))]
+  #[ensures(result.is_ok() ==> self.nunacked() == 0)]
+  pub fn begin(&mut self, isn: u64) -> MyResult<()> {
+    self.isn = Some(isn);
+    self.next = next_seq(isn);
+    self.unacked.clear();
+    self.rewrite()
+  }
+
+  /// Log data under seq, must be called before it is sent for the first time
+  #[requires(self.isn().is_some())]
+  #[ensures(result.is_ok() ==> self.nunacked() == old(self.nunacked()) + 1)]
+  pub fn log_send(&mut self, seq: u64, data: Vec<u8>) -> MyResult<()> {
+    if let Error(e) = self.append(SEND_RECORD, seq, &data) {
+      return Error(e);
+    }
+    self.apply(SEND_RECORD, seq, data);
+    Value(())
+  }
+
+  /// Log the ack of seq, the message is no longer replayed
+  #[ensures(result.is_ok() ==> self.nunacked() <= old(self.nunacked()))]
+  pub fn log_ack(&mut self, seq: u64) -> MyResult<()> {
+    if !self.unacked.iter().any(|(unacked, _)| *unacked == seq) {
+      return Value(());
+    }
+    if let Error(e) = self.append(ACK_RECORD, seq, &[]) {
+      return Error(e);
+    }
+    self.apply(ACK_RECORD, seq, Vec::new());
+    if self.records >= COMPACT_AFTER {
+      return self.rewrite();
+    }
+    Value(())
+  }
+
+  fn apply(&mut self, kind: u8, seq: u64, data: Vec<u8>) {
+    match kind {
+      LINK_RECORD => {
+        self.isn = Some(seq);
+        self.next = next_seq(seq);
+        self.unacked.clear();
+      },
+      SEND_RECORD => {
+        // Replays log nothing new, only fresh seqs move next
+        if seq == self.next {
+          self.next = next_seq(seq);
+        }
+        if !self.unacked.iter().any(|(unacked, _)| *unacked == seq) {
+          self.unacked.push((seq, data));
+        }
+      },
+      ACK_RECORD => self.unacked.retain(|(unacked, _)| *unacked != seq),
+      NEXT_RECORD => self.next = seq,
+      _ => {},
+    }
+  }
+
+  fn append(&mut self, kind: u8, seq: u64, data: &[u8]) -> MyResult<()> {
+    let record = encode(kind, seq, data);
+    if self.log.write_all(&record).is_err() || self.log.sync_data().is_err() {
+      return Error(SenderError::StorageError);
+    }
+    self.records += 1;
+    Value(())
+  }
+
+  /// Replace the log with the records of the current state, with write_atomically
+  /// so a crash leaves either the old or the new one
+  fn rewrite(&mut self) -> MyResult<()> {
+    let mut buf = Vec::new();
+    let mut records = 0;
+    if let Some(isn) = self.isn {
+      buf.extend(encode(LINK_RECORD, isn, &[]));
+      buf.extend(encode(NEXT_RECORD, self.next, &[]));
+      records += 2;
+    }
+    for (seq, data) in self.unacked.iter() {
+      buf.extend(encode(SEND_RECORD, *seq, data));
+      records += 1;
+    }
+    if write_atomically(&self.path, &buf).is_err() {
+      return Error(SenderError::StorageError);
+    }
+    self.log = match OpenOptions::new().append(true).open(&self.path) {
+      Ok(log) => log,
+      Err(_) => return Error(SenderError::StorageError),
+    };
+    self.records = records;
+    Value(())
+  }
+}
+
+fn encode(kind: u8, seq: u64, data: &[u8]) -> Vec<u8> {
+  let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + data.len());
+  record.push(kind);
+  record.extend_from_slice(&seq.to_be_bytes());
+  record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+  record.extend_from_slice(data);
+  record
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs::{self, OpenOptions};
+  use std::io::Write;
+
+  use super::Outbox;
+
+  #[test]
+  fn test_outbox_recovers_unacked() {
+    let path = std::env::temp_dir().join(format!("plink-outbox-{}.log", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut outbox = Outbox::open(&path).unwrap();
+    assert_eq!(outbox.isn(), None);
+    outbox.begin(u64::MAX - 1).unwrap();
+    outbox.log_send(u64::MAX, vec![1]).unwrap();
+    outbox.log_send(0, vec![2]).unwrap();
+    outbox.log_send(1, vec![3]).unwrap();
+    outbox.log_ack(0).unwrap();
+    drop(outbox);
+    // A crash in the middle of the next append
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[1, 0, 0, 0]).unwrap();
+    let mut outbox = Outbox::open(&path).unwrap();
+    assert_eq!(outbox.isn(), Some(u64::MAX - 1));
+    assert_eq!(outbox.next(), 2);
+    assert_eq!(outbox.unacked(), &[(u64::MAX, vec![1]), (1, vec![3])]);
+    // Replaying under the original seq does not log it twice
+    outbox.log_send(u64::MAX, vec![1]).unwrap();
+    outbox.log_ack(u64::MAX).unwrap();
+    outbox.log_ack(1).unwrap();
+    outbox.begin(7).unwrap();
+    let outbox = Outbox::open(&path).unwrap();
+    assert_eq!((outbox.isn(), outbox.next(), outbox.nunacked()), (Some(7), 8, 0));
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,197 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use prusti_contracts::*;
+use rand::random;
+
+use super::state::error::Result::{self, *};
+use super::SenderError;
+
+/// How the sender retries opening the transport while the peer is not up yet,
+/// e.g. during a restart. Delays grow exponentially up to max_delay, and part of each one
+/// is drawn at random so that senders cut off together do not all come back at once
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+  // Delay after the first failed attempt
+  initial_delay: Duration,
+  // Factor applied to the delay after every further failed attempt, finite and at least 1
+  multiplier: f64,
+  max_delay: Duration,
+  // Fraction of each delay taken off at random, within [0, 1]
+  jitter: f64,
+  // No attempt starts once this long has passed since the first one, None retries forever
+  deadline: Option<Duration>,
+}
+
+impl ReconnectPolicy {
+  /// A single attempt, a peer that is not up is reported to the caller right away
+  pub fn never() -> Self {
+    ReconnectPolicy {
+      initial_delay: Duration::ZERO,
+      multiplier: 1.0,
+      max_delay: Duration::ZERO,
+      jitter: 0.0,
+      deadline: Some(Duration::ZERO),
+    }
+  }
+
+  /// Delays doubling from initial_delay up to max_delay, with half of each one jittered
+  pub fn exponential(initial_delay: Duration, max_delay: Duration, deadline: Option<Duration>) -> Self {
+    ReconnectPolicy { initial_delay, multiplier: 2.0, max_delay, jitter: 0.5, deadline }
+  }
+
+  /// Grow the delay by multiplier after every further failed attempt and take up to the fraction
+  /// jitter of each one off at random, 0 waits the full delay, 1 anything up to it.
+  /// InvalidPolicy unless multiplier is finite and at least 1 and jitter is within [0, 1]
+  #[ensures(result.is_ok() ==> result.unwrap().multiplier() == multiplier)]
+  #[ensures(result.is_ok() ==> result.unwrap().jitter() == jitter)]
+  pub fn with_backoff(mut self, multiplier: f64, jitter: f64) -> Result<Self> {
+    if !multiplier.is_finite() || multiplier < 1.0 || !(0.0..=1.0).contains(&jitter) {
+      return Error(SenderError::InvalidPolicy);
+    }
+    self.multiplier = multiplier;
+    self.jitter = jitter;
+    Value(self)
+  }
+
+  #[pure]
+  pub fn initial_delay(&self) -> Duration {
+    self.initial_delay
+  }
+
+  #[pure]
+  pub fn multiplier(&self) -> f64 {
+    self.multiplier
+  }
+
+  #[pure]
+  pub fn max_delay(&self) -> Duration {
+    self.max_delay
+  }
+
+  #[pure]
+  pub fn jitter(&self) -> f64 {
+    self.jitter
+  }
+
+  #[pure]
+  pub fn deadline(&self) -> Option<Duration> {
+    self.deadline
+  }
+
+  /// Delay after failures failed attempts, before jitter
+  #[pure]
+  #[trusted]
+  #[requires(failures > 0)]
+  #[ensures(result <= self.max_delay)]
+  pub fn delay(&self, failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(i32::MAX as u32) as i32;
+    let factor = self.multiplier.powi(exponent);
+    let delay = self.initial_delay.as_secs_f64() * factor;
+    if delay.is_finite() && delay < self.max_delay.as_secs_f64() {
+      Duration::from_secs_f64(delay)
+    } else {
+      self.max_delay
+    }
+  }
+
+  /// Delay after failures failed attempts, jitter included
+  #[requires(failures > 0)]
+  #[ensures(result <= self.delay(failures))]
+  pub fn jittered(&self, failures: u32) -> Duration {
+    let delay = self.delay(failures);
+    delay.mul_f64(1.0 - self.jitter * random::<f64>())
+  }
+
+  /// Call attempt until it gets past opening the transport, sleeping between attempts.
+  /// Only SocketError is retried, it means the peer could not be reached at all
+  pub(crate) fn run<V, F: FnMut() -> Result<V>>(&self, mut attempt: F) -> Result<V> {
+    let start = Instant::now();
+    let mut failures = 0;
+    loop {
+      match attempt() {
+        Error(SenderError::SocketError) => failures += 1,
+        res => return res,
+      }
+      let remaining = match self.deadline {
+        Some(deadline) => deadline.saturating_sub(start.elapsed()),
+        None => Duration::MAX,
+      };
+      if remaining.is_zero() {
+        return Error(SenderError::SocketError);
+      }
+      thread::sleep(self.jittered(failures).min(remaining));
+    }
+  }
+}
+
+impl Default for ReconnectPolicy {
+  fn default() -> Self {
+    ReconnectPolicy::never()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+
+  use super::super::state::error::Result::{Error, Value};
+  use super::super::SenderError;
+  use super::ReconnectPolicy;
+
+  #[test]
+  fn test_delays_grow_up_to_max() {
+    let policy = ReconnectPolicy::exponential(Duration::from_millis(10), Duration::from_millis(50), None);
+    assert_eq!(policy.delay(1), Duration::from_millis(10));
+    assert_eq!(policy.delay(3), Duration::from_millis(40));
+    assert_eq!(policy.delay(4), Duration::from_millis(50));
+    assert_eq!(policy.delay(u32::MAX), Duration::from_millis(50));
+    for failures in 1..10 {
+      let jittered = policy.jittered(failures);
+      assert!(jittered <= policy.delay(failures) && jittered >= policy.delay(failures) / 2);
+    }
+  }
+
+  #[test]
+  fn test_backoff_is_validated() {
+    let policy = ReconnectPolicy::exponential(Duration::from_millis(10), Duration::from_secs(1), None);
+    let tripling = policy.clone().with_backoff(3.0, 0.0).unwrap();
+    assert_eq!(tripling.delay(3), Duration::from_millis(90));
+    assert_eq!(tripling.jittered(3), Duration::from_millis(90));
+    for (multiplier, jitter) in [(f64::NAN, 0.5), (f64::INFINITY, 0.5), (0.5, 0.5), (2.0, f64::NAN), (2.0, -0.1), (2.0, 1.5)] {
+      match policy.clone().with_backoff(multiplier, jitter) {
+        Error(SenderError::InvalidPolicy) => {},
+        _ => panic!("Expected {} and {} to be rejected\n", multiplier, jitter),
+      }
+    }
+  }
+
+  #[test]
+  fn test_run_stops_at_deadline() {
+    let deadline = Duration::from_millis(30);
+    let policy = ReconnectPolicy::exponential(Duration::from_millis(1), Duration::from_millis(5), Some(deadline));
+    let start = Instant::now();
+    let mut attempts = 0;
+    match policy.run::<(), _>(|| {
+      attempts += 1;
+      Error(SenderError::SocketError)
+    }) {
+      Error(SenderError::SocketError) => {},
+      _ => panic!("Expected to give up\n"),
+    }
+    assert!(start.elapsed() >= deadline);
+    assert!(attempts > 2);
+    // Other errors are not about reaching the peer and are not retried
+    let mut attempts = 0;
+    let res = ReconnectPolicy::exponential(Duration::from_millis(1), Duration::from_millis(5), None).run::<(), _>(|| {
+      attempts += 1;
+      Error(SenderError::NoResponse)
+    });
+    assert!(res.is_err() && attempts == 1);
+    let mut attempts = 0;
+    match ReconnectPolicy::never().run(|| { attempts += 1; Value(attempts) }) {
+      Value(1) => {},
+      _ => panic!("Expected a single attempt\n"),
+    }
+  }
+}
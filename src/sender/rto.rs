@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use prusti_contracts::*;
+
+/// Lower bound of the retransmission timeout, keeps a fast link from timing out on scheduling noise
+pub const MIN_RTO: Duration = Duration::from_millis(10);
+/// Upper bound of the retransmission timeout, backoff stops growing there
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// Retransmission timeout derived from measured round trip times, following RFC 6298:
+/// a smoothed RTT and its mean deviation, RTO = SRTT + 4 * RTTVAR, doubled on every
+/// retransmission until the message is acknowledged
+#[derive(Clone, Debug)]
+pub struct RttEstimator {
+  srtt: Option<Duration>,
+  rttvar: Duration,
+  // Before backoff
+  base: Duration,
+  backoffs: u32,
+}
+
+impl RttEstimator {
+  /// Estimator that uses initial until the first RTT is measured
+  pub fn new(initial: Duration) -> Self {
+    RttEstimator { srtt: None, rttvar: Duration::ZERO, base: clamp(initial), backoffs: 0 }
+  }
+
+  /// Timeout to wait for the next ack, backoff included
+  #[pure]
+  #[trusted]
+  pub fn rto(&self) -> Duration {
+    let factor = 1u32.checked_shl(self.backoffs).unwrap_or(u32::MAX);
+    match self.base.checked_mul(factor) {
+      Some(rto) => rto.min(MAX_RTO),
+      None => MAX_RTO,
+    }
+  }
+
+  /// Smoothed round trip time, None until the first sample
+  #[pure]
+  pub fn srtt(&self) -> Option<Duration> {
+    self.srtt
+  }
+
+  /// Mean deviation of the round trip time
+  #[pure]
+  pub fn rttvar(&self) -> Duration {
+    self.rttvar
+  }
+
+  /// Retransmissions since the last sample
+  #[pure]
+  pub fn backoffs(&self) -> u32 {
+    self.backoffs
+  }
+
+  /// Account for the round trip of a message acknowledged on its first transmission.
+  /// Retransmitted messages must not be sampled, their ack could answer any transmission (Karn)
+  #[ensures(self.srtt().is_some())]
+  #[ensures(self.backoffs() == 0)]
+  pub fn sample(&mut self, rtt: Duration) {
+    match self.srtt {
+      None => {
+        self.srtt = Some(rtt);
+        self.rttvar = rtt / 2;
+      },
+      Some(srtt) => {
+        let delta = srtt.abs_diff(rtt);
+        self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+        self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+      },
+    }
+    self.base = clamp(self.srtt.unwrap() + self.rttvar * 4);
+    self.backoffs = 0;
+  }
+
+  /// A retransmitted message got through, the next one starts from the estimated timeout again.
+  /// Nothing is sampled, the estimate only moves with first transmissions
+  #[ensures(self.backoffs() == 0)]
+  pub fn reset_backoff(&mut self) {
+    self.backoffs = 0;
+  }
+
+  /// A timeout ran out, wait twice as long for the retransmission
+  #[ensures(self.rto() >= old(self.rto()))]
+  pub fn backoff(&mut self) {
+    if self.rto() < MAX_RTO {
+      self.backoffs += 1;
+    }
+  }
+}
+
+fn clamp(rto: Duration) -> Duration {
+  rto.max(MIN_RTO).min(MAX_RTO)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::{RttEstimator, MAX_RTO, MIN_RTO};
+
+  #[test]
+  fn test_rto_follows_samples_and_backs_off() {
+    let mut rtt = RttEstimator::new(Duration::from_secs(1));
+    assert_eq!(rtt.rto(), Duration::from_secs(1));
+    assert_eq!(rtt.srtt(), None);
+    rtt.sample(Duration::from_millis(100));
+    assert_eq!(rtt.srtt(), Some(Duration::from_millis(100)));
+    assert_eq!(rtt.rttvar(), Duration::from_millis(50));
+    assert_eq!(rtt.rto(), Duration::from_millis(300));
+    rtt.sample(Duration::from_millis(180));
+    assert_eq!(rtt.srtt(), Some(Duration::from_millis(110)));
+    assert_eq!(rtt.rttvar(), Duration::from_millis(57) + Duration::from_micros(500));
+    assert_eq!(rtt.rto(), Duration::from_millis(340));
+    rtt.backoff();
+    rtt.backoff();
+    assert_eq!(rtt.rto(), Duration::from_millis(1360));
+    rtt.reset_backoff();
+    assert_eq!(rtt.rto(), Duration::from_millis(340));
+    for _ in 0..100 {
+      rtt.sample(Duration::from_micros(10));
+    }
+    assert_eq!(rtt.rto(), MIN_RTO);
+    for _ in 0..40 {
+      rtt.backoff();
+    }
+    assert_eq!(rtt.rto(), MAX_RTO);
+  }
+}
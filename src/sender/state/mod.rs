@@ -1,98 +1,322 @@
 // use super::*;
 pub mod error;
+pub mod selective;
+pub mod window;
 
 
-use std::time::Duration;
-use std::u8::MAX;
+use std::time::{Duration, Instant};
 
 use prusti_contracts::*;
 use rand::{random, seq};
+use crate::messaging::Message;
 use crate::types::socket::{Socket, SocketError};
-use crate::DATA_SIZE;
-use crate::types::MyResult;
+use crate::types::transport::{recv_until, Transport};
+use crate::types::{MyResult, next_seq, seq_lt};
 use self::error::Result::{self, *};
 
 #[derive(Clone, Debug)]
 pub enum SenderError {
-    SendError{data: u8},
+    SendError{seq: u64},
     SocketError,
     NoResponse,
     IllegalState,
     Timeout,
     BadTimeoutInput,
+    /// The outbox of a crash-recovery sender could not be read or written
+    StorageError,
+    /// The message does not fit in a frame, sending it again cannot help
+    MessageTooLarge{seq: u64},
+    /// The receiver will not serve the announced link, see Message::Refuse
+    Refused,
+    /// A reconnect policy was given a multiplier below 1 or a jitter outside [0, 1], NaN included
+    InvalidPolicy,
 }
 
 use SenderError::*;
 
-pub trait ReadyTrait {
-  fn send(self, data: u8) -> Result<Pending>;
+pub trait ReadyTrait<T: Transport = Socket> {
+  fn send(self, data: Vec<u8>) -> Result<Pending<T>>;
 }
 
-pub struct Connect {
-  seq: u8,
-  remote_addr: String
+pub struct Connect<T: Transport = Socket> {
+  seq: u64,
+  remote_addr: String,
+  socket: T,
+  // Window advertised in the handshake ack
+  rwnd: u32,
+  // Seq to go on at when the link is resumed with Message::Resume
+  resume: Option<u64>,
+  // Announcement of the peer to acknowledge again while waiting, with the window to advertise
+  answer: Option<(u64, u32)>
 }
 
-pub struct Ready {
-  seq: u8,
-  socket: Socket
+pub struct Ready<T: Transport = Socket> {
+  seq: u64,
+  socket: T,
+  // Free slots the receiver advertised in its last ack
+  rwnd: u32
 }
 
-pub struct Pending {
-  seq: u8,
-  socket: Socket,
-  data: u8
+pub struct Pending<T: Transport = Socket> {
+  seq: u64,
+  socket: T,
+  data: Vec<u8>,
+  rwnd: u32
+}
+
+/// What Pending::poll read, for links that carry data both ways
+pub enum Polled<T: Transport = Socket> {
+  /// The message was acknowledged. A DataAck whose ack covered it is passed on for its data
+  Delivered(Ready<T>, Option<Message>),
+  /// A frame that does not acknowledge the message, e.g. data of the peer
+  Frame(Pending<T>, Message),
+  /// Nothing arrived in time
+  Expired(Pending<T>),
 }
 
 
-pub fn connect(remote_addr: String) -> Result<Ready> {
-  let socket = Socket::connect(remote_addr);
+/// Open the transport and pick a random initial sequence number.
+/// The link is only usable once the receiver has acknowledged it, see Connect::handshake
+pub fn connect<T: Transport>(remote_addr: String) -> Result<Connect<T>> {
+  let socket = T::connect(remote_addr.clone());
   match socket {
     MyResult::Value(socket) => {
-      let seq = random::<u8>();
-      Value(Ready {seq, socket})
+      let seq = random::<u64>();
+      Value(Connect {seq, remote_addr, socket, rwnd: 0, resume: None, answer: None})
     },
     MyResult::Error(_) => Error(SocketError)
   }
 }
 
+/// Open a new transport for the link whose initial sequence number is isn and go on at seq,
+/// e.g. after the previous connection dropped or the sender restarted with its outbox.
+/// The receiver gives up on the seqs before seq it has not delivered, and refuses a link
+/// it no longer knows
+pub fn resume<T: Transport>(remote_addr: String, isn: u64, seq: u64) -> Result<Connect<T>> {
+  match T::connect(remote_addr.clone()) {
+    MyResult::Value(socket) => Value(Connect {seq: isn, remote_addr, socket, rwnd: 0, resume: Some(seq), answer: None}),
+    MyResult::Error(_) => Error(SocketError)
+  }
+}
 
-impl Ready {
-  #[ensures(result.is_ok() ==> snap(&self).socket.nsent() + 1 == result.unwrap().socket.nsent())]
-  pub fn send(mut self, data: u8) -> Result<Pending> {
-    let res = self.socket.send(data);
-    match res {
-      MyResult::Value(bs) => {
-        if bs == DATA_SIZE {
-          Value(Pending {seq: self.seq, socket: self.socket, data})
-        } else {
-          Error(SendError{data})
+/// Announce a link with a random initial sequence number over a transport that is already open,
+/// e.g. one a Duplex accepted. Its remote address is unknown and left empty
+pub fn announce_on<T: Transport>(socket: T) -> Connect<T> {
+  Connect {seq: random::<u64>(), remote_addr: String::new(), socket, rwnd: 0, resume: None, answer: None}
+}
+
+impl<T: Transport> Connect<T> {
+  /// While waiting for the handshake ack, acknowledge the peer's own announcement of isn again.
+  /// Both ends of a link that carries data both ways announce, and either ack may be lost
+  pub fn answering(mut self, isn: u64, window: u32) -> Self {
+    self.answer = Some((isn, window));
+    self
+  }
+
+  /// Initial sequence number announced to the receiver
+  #[pure]
+  pub fn seq(&self) -> u64 {
+    self.seq
+  }
+
+  pub fn remote_addr(&self) -> &str {
+    &self.remote_addr
+  }
+
+  /// Announce the initial sequence number and wait for the receiver to acknowledge it,
+  /// retransmitting the announcement up to attempts times.
+  /// Like a TCP SYN, the announcement consumes a sequence number so that a late
+  /// handshake ack can never be taken for the ack of the first data message
+  /// A resumed link goes on at the seq given to resume instead. Refused if the receiver answers Refuse
+  #[ensures(result.is_ok() && old(self.resume).is_none() ==> result.unwrap().seq() == next_seq(old(self.seq)))]
+  #[ensures(result.is_ok() && old(self.resume).is_some() ==> result.unwrap().seq() == old(self.resume).unwrap())]
+  pub fn handshake(mut self, timeout: Duration, attempts: usize) -> Result<Ready<T>> {
+    let mut attempt = 0;
+    while attempt < attempts {
+      match self.announce(timeout) {
+        Value(true) => {
+          let seq = self.resume.unwrap_or(next_seq(self.seq));
+          return Value(Ready {seq, socket: self.socket, rwnd: self.rwnd});
+        },
+        Value(false) => attempt += 1,
+        Error(e) => return Error(e)
+      }
+    }
+    Error(Timeout)
+  }
+
+  /// Send the initial sequence number once and wait up to timeout for its ack
+  fn announce(&mut self, timeout: Duration) -> Result<bool> {
+    let r = self.socket.set_read_timeout(timeout);
+    if r.is_err() {
+      return Error(BadTimeoutInput);
+    }
+    let isn = self.seq;
+    let announcement = match self.resume {
+      Some(seq) => Message::Resume {id: isn, seq},
+      None => Message::Connect {id: isn},
+    };
+    if self.socket.send_frame(announcement).is_err() {
+      return Error(SocketError);
+    }
+    let t0 = std::time::Instant::now();
+    loop {
+      match self.socket.recv_frame() {
+        MyResult::Value(Message::Ack {id, window}) if id == isn => {
+          self.rwnd = window;
+          return Value(true);
+        },
+        MyResult::Value(Message::Refuse {id}) if id == isn => return Error(Refused),
+        MyResult::Value(msg) => {
+          if let (Message::Connect {id}, Some((peer, window))) = (msg, self.answer) {
+            if id == peer && self.socket.send_frame(Message::Ack {id, window}).is_err() {
+              return Error(SocketError);
+            }
+          }
+          let remaining = timeout.saturating_sub(t0.elapsed());
+          if remaining.as_millis() == 0 {
+            return Value(false);
+          }
+          if self.socket.set_read_timeout(remaining).is_err() {
+            return Error(BadTimeoutInput);
+          }
+        },
+        MyResult::Error(SocketError::Timeout) => return Value(false),
+        MyResult::Error(_) => return Error(NoResponse)
+      }
+    }
+  }
+}
+
+
+impl<T: Transport> Ready<T> {
+  /// Sequence number the next message will be sent with
+  #[pure]
+  pub fn seq(&self) -> u64 {
+    self.seq
+  }
+
+  /// Window the receiver advertised last, nothing should be sent while it is 0
+  #[pure]
+  pub fn rwnd(&self) -> u32 {
+    self.rwnd
+  }
+
+  /// Ask the receiver for its window and wait up to timeout for the answer.
+  /// Acks of earlier messages update the window as well
+  #[ensures(result.is_ok() ==> result.unwrap().seq() == old(self.seq))]
+  pub fn probe(mut self, timeout: Duration) -> Result<Ready<T>> {
+    if self.socket.set_read_timeout(timeout).is_err() {
+      return Error(BadTimeoutInput);
+    }
+    if self.socket.send_frame(Message::Probe {id: self.seq}).is_err() {
+      return Error(SocketError);
+    }
+    let t0 = std::time::Instant::now();
+    loop {
+      match self.socket.recv_frame() {
+        MyResult::Value(Message::Ack {window, ..}) => {
+          self.rwnd = window;
+          return Value(self);
+        },
+        MyResult::Value(_) => {
+          let remaining = timeout.saturating_sub(t0.elapsed());
+          if remaining.as_millis() == 0 || self.socket.set_read_timeout(remaining).is_err() {
+            return Value(self);
+          }
+        },
+        MyResult::Error(SocketError::Timeout) => return Value(self),
+        MyResult::Error(_) => return Error(NoResponse)
+      }
+    }
+  }
+
+  /// Next frame of the peer within timeout, None if nothing arrived in time.
+  /// Acks of earlier messages update the window
+  pub fn poll(&mut self, timeout: Duration) -> Result<Option<Message>> {
+    match recv_within(&mut self.socket, timeout) {
+      Value(Some(msg)) => {
+        if let Message::Ack {window, ..} | Message::DataAck {window, ..} = msg {
+          self.rwnd = window;
         }
+        Value(Some(msg))
       },
-      MyResult::Error(_) => Error(SendError{data})
+      res => res,
+    }
+  }
+
+  /// Acknowledge the peer's messages up to ack on a link that carries data both ways
+  pub fn send_ack(&mut self, ack: u64, window: u32) -> Result<()> {
+    match self.socket.send_frame(Message::Ack {id: ack, window}) {
+      MyResult::Value(_) => Value(()),
+      MyResult::Error(_) => Error(SendError{seq: self.seq})
+    }
+  }
+
+  /// Like send, the frame is a DataAck that also acknowledges the peer's messages up to ack
+  #[ensures(result.is_ok() ==> result.unwrap().seq() == old(self.seq))]
+  pub fn send_acking(mut self, data: Vec<u8>, ack: u64, window: u32) -> Result<Pending<T>> {
+    let seq = self.seq;
+    match self.socket.send_frame(Message::DataAck {id: seq, data: data.clone(), ack, window}) {
+      MyResult::Value(_) => Value(Pending {seq, socket: self.socket, data, rwnd: self.rwnd}),
+      MyResult::Error(SocketError::FrameTooLarge) => Error(MessageTooLarge{seq}),
+      MyResult::Error(_) => Error(SendError{seq})
+    }
+  }
+
+  /// Hand the transport back once the handshake is done, for a sender that keeps its own
+  /// sequence spaces, e.g. one per channel of a MuxSender
+  pub fn into_transport(self) -> T {
+    self.socket
+  }
+
+  /// Continue at seq, e.g. to replay a message under its original seq after a restart
+  #[ensures(result.seq() == seq)]
+  pub fn skip_to(self, seq: u64) -> Ready<T> {
+    Ready {seq, socket: self.socket, rwnd: self.rwnd}
+  }
+
+  #[ensures(result.is_ok() ==> snap(&self).socket.nsent() + 1 == result.unwrap().socket.nsent())]
+  #[ensures(result.is_ok() ==> result.unwrap().seq() == old(self.seq))]
+  pub fn send(mut self, data: Vec<u8>) -> Result<Pending<T>> {
+    let seq = self.seq;
+    let res = self.socket.send_frame(Message::Data {id: seq, data: data.clone()});
+    match res {
+      MyResult::Value(_) => Value(Pending {seq, socket: self.socket, data, rwnd: self.rwnd}),
+      MyResult::Error(SocketError::FrameTooLarge) => Error(MessageTooLarge{seq}),
+      MyResult::Error(_) => Error(SendError{seq})
     }
   }
 }
 
-impl Pending {
+impl<T: Transport> Pending<T> {
+  /// Sequence number of the message waiting for its acknowledgement
+  #[pure]
+  pub fn seq(&self) -> u64 {
+    self.seq
+  }
+
+  pub fn data(&self) -> &[u8] {
+    &self.data
+  }
 
   // #[ensures(result.is_ok() ==> result.unwrap().socket.nrecv() == snap(&self).socket.nrecv() + 1)]
   //$$ Delivered
   // #[ensures(result[0].is_ok() && result[1] ==> result[0].unwrap().socket.nrecv() == snap(&self).socket.nrecv() + 1)]
-  #[ensures(result[0].is_ok() && result[1] ==> snap(&self).seq == (result[0].seq + 1) % std::u8::MAX)]
+  #[ensures(result.0.is_ok() && result.1 ==> result.0.unwrap().seq() == next_seq(old(self.seq)))]
   //$$ Timeout
   // #[ensures(result[0].is_ok() && !result[1] ==> result[0].unwrap().socket.nrecv() == snap(&self).socket.nrecv())]
-  #[ensures(result[0].is_ok() && !result[1] ==> snap(&self).seq == result[0].seq)]
+  #[ensures(result.0.is_ok() && !result.1 ==> result.0.unwrap().seq() == old(self.seq))]
   //$$ Error
   // Might add something to socket state to check if it is in illegal state
-  #[ensures(!result[0].is_ok() ==> result[0].unwrap().socket.nrecv() == snap(&self).socket.nrecv())]
+  #[ensures(!result.0.is_ok() ==> !result.1)]
 
   // Define named predicate for result[0].unwrap().socket.nrecv() == snap(&self).socket.nrecv() + 1) and 
   // result[0].unwrap().socket.nrecv() == snap(&self).socket.nrecv()) so that it can be parsed
   // the first would transition from "sent" to "delivered" on network, the other would not do anything 
   // Also might require defining names for possible outcomes (eg. "Delivered, Timeout")
 
-  pub fn wait_deliver(mut self, timeout: Duration) -> (Result<Ready>, bool) {
+  pub fn wait_deliver(mut self, timeout: Duration) -> (Result<Ready<T>>, bool) {
     // Handling timeout done with bool at the moment
     // Could be replaced by Result<Ready, Error> in the future
     // Or could introduce a field from_timeout to Ready
@@ -105,101 +329,176 @@ impl Pending {
     }
     let seq = self.seq.clone();
     let t0 = std::time::Instant::now();
-    let res = self.socket.recv();
+    let res = self.socket.recv_frame();
     match res {
-      MyResult::Value(n) => {
-        if n == seq {
-          let next_seq = (seq + 1) % std::u8::MAX;
-          (Value(Ready {socket: self.socket, seq: next_seq}), true)
+      // Acks are cumulative, any ack at or past seq covers it
+      MyResult::Value(Message::Ack {id, window}) if !seq_lt(id, seq) => {
+        (Value(Ready {socket: self.socket, seq: next_seq(seq), rwnd: window}), true)
+      },
+      MyResult::Value(_) => {
+        // Stale acks of earlier messages are skipped
+        let delta = std::time::Instant::now().duration_since(t0);
+        let timeout1 = timeout.saturating_sub(delta);
+        if timeout1.as_millis() > 0 {
+          self.wait_deliver(timeout1)
         } else {
-          let delta = std::time::Instant::now().duration_since(t0);
-          let timeout1 = timeout - delta;
-          if timeout1.as_millis() > 0 {
-            self.wait_deliver(timeout1)
-          } else {
-            (Value(Ready {socket: self.socket, seq}), false)
-          }
+          (Value(Ready {socket: self.socket, seq, rwnd: self.rwnd}), false)
         }
       },
       MyResult::Error(SocketError::Timeout) => {
-        (Value(Ready {socket: self.socket, seq}), false)
+        (Value(Ready {socket: self.socket, seq, rwnd: self.rwnd}), false)
       },
       MyResult::Error(_) => (Error(SenderError::NoResponse), false)
     }
   }
 }
 
+impl<T: Transport> Pending<T> {
+  /// Read the next frame within timeout, for links that carry data both ways.
+  /// Both Ack and DataAck frames acknowledge the message, cumulatively
+  pub fn poll(mut self, timeout: Duration) -> Result<Polled<T>> {
+    let seq = self.seq;
+    match recv_within(&mut self.socket, timeout) {
+      Value(Some(Message::Ack {id, window})) if !seq_lt(id, seq) => {
+        Value(Polled::Delivered(Ready {socket: self.socket, seq: next_seq(seq), rwnd: window}, None))
+      },
+      Value(Some(Message::DataAck {id, data, ack, window})) if !seq_lt(ack, seq) => {
+        let msg = Message::DataAck {id, data, ack, window};
+        Value(Polled::Delivered(Ready {socket: self.socket, seq: next_seq(seq), rwnd: window}, Some(msg)))
+      },
+      Value(Some(msg)) => Value(Polled::Frame(self, msg)),
+      Value(None) => Value(Polled::Expired(self)),
+      Error(e) => Error(e)
+    }
+  }
+
+  /// Send the message again as a DataAck acknowledging the peer's messages up to ack
+  pub fn resend_acking(&mut self, ack: u64, window: u32) -> Result<()> {
+    let frame = Message::DataAck {id: self.seq, data: self.data.clone(), ack, window};
+    match self.socket.send_frame(frame) {
+      MyResult::Value(_) => Value(()),
+      MyResult::Error(_) => Error(SendError{seq: self.seq})
+    }
+  }
+
+  /// Acknowledge the peer's messages up to ack while the message waits for its own ack
+  pub fn send_ack(&mut self, ack: u64, window: u32) -> Result<()> {
+    match self.socket.send_frame(Message::Ack {id: ack, window}) {
+      MyResult::Value(_) => Value(()),
+      MyResult::Error(_) => Error(SendError{seq: self.seq})
+    }
+  }
+}
+
+/// Next frame within timeout, None once it has passed
+fn recv_within<T: Transport>(socket: &mut T, timeout: Duration) -> Result<Option<Message>> {
+  match recv_until(socket, Instant::now() + timeout) {
+    MyResult::Value(msg) => Value(msg),
+    MyResult::Error(SocketError::SetTimeoutFailed) => Error(BadTimeoutInput),
+    MyResult::Error(_) => Error(NoResponse)
+  }
+}
+
 impl SenderError {
   fn to_string(&self) -> String {
     match self {
-      SendError{data} => "Failed to send data: ".to_string() + &data.to_string(),
+      SendError{seq} => "Failed to send message: ".to_string() + &seq.to_string(),
       SocketError => "Failed to connect".to_string(),
       NoResponse => "No response".to_string(),
       IllegalState => "Illegal state".to_string(),
       Timeout => "Timeout".to_string(),
       BadTimeoutInput => "BadTimeoutInput".to_string(),
+      StorageError => "Storage error".to_string(),
+      MessageTooLarge{seq} => "Message too large: ".to_string() + &seq.to_string(),
+      Refused => "Refused".to_string(),
+      InvalidPolicy => "Invalid policy".to_string(),
     }
   }
 }
 
 #[cfg(test)]
 mod tests {
-  use std::{io::{Read, Write}, net::TcpListener, thread, time::Duration};
+  use std::{thread, time::Duration};
+
+  use crate::types::loopback::{Loopback, LoopbackListener};
+  use crate::types::transport::Listener;
 
 use super::*;
 
   #[test]
   fn test_send() {
-    let remote_addr = "localhost:8080".to_string();
+    let remote_addr = "sender-send".to_string();
     print!("Connecting to {}\n", remote_addr);
-    let tj = thread::spawn(|| {
-      let listener = TcpListener::bind("localhost:8080").unwrap();
-      let (mut stream, addr) = listener.accept().unwrap();
-      print!("Accepted connection from {}\n", addr);
-      let mut buffer = [0; 1];
-      let n = stream.read(&mut buffer);
-      if n.is_err() {
-        panic!("Error reading...\n")
+    let listener = LoopbackListener::bind(remote_addr.clone()).unwrap();
+    let tj = thread::spawn(move || {
+      let mut socket = listener.accept().unwrap();
+      print!("Accepted connection\n");
+      match socket.recv_frame() {
+        MyResult::Value(Message::Connect {id}) => socket.send_frame(Message::Ack {id, window: 1}).unwrap(),
+        _ => panic!("Expected handshake\n")
+      };
+      match socket.recv_frame() {
+        MyResult::Value(Message::Data {id, data}) => {
+          print!("Received {:?}\n", data);
+          socket.send_frame(Message::Ack {id, window: 1}).unwrap();
+          print!("Sent ACK\n");
+        },
+        _ => panic!("Error reading...\n")
       }
-      print!("Received {}\n", buffer[0]);
-      stream.write(&buffer);
-      print!("Sent ACK\n");
     });
-    thread::sleep(Duration::from_secs(2));
-    let res = connect(remote_addr);
+    let res = connect::<Loopback>(remote_addr).unwrap().handshake(Duration::from_secs(10), 3);
     print!("Connected\n");
-    let data = 1;
-    let res = res.unwrap().send(data);
+    let data = vec![1, 2, 3];
+    let res = res.unwrap().send(data.clone());
     match res {
       Value(pending) => {
-        print!("Sent data {}\n", data);
+        print!("Sent data {:?}\n", data);
         match pending.wait_deliver(Duration::from_secs(10)) {
           (Value(ready), true) => print!("Data delivered, terminating sender...\n"),
-          (Value(ready), false) => print!("Timeout, resending required...\n"),
-          _ => print!("Other error....\n"),
+          (Value(ready), false) => panic!("Timeout, resending required...\n"),
+          _ => panic!("Other error....\n"),
         }
       },
-      Error(e) => print!("Error when sending...\n")
-    }
-    
-    
-    // match res {
-    //   Value(ready) => {
-    //     let res = ready.send(1);
-    //     match res {
-    //       Value(pending) => {
-    //         let res = pending.wait_deliver();
-    //         match res {
-    //           Value(_) => {},
-    //           Error(e) => panic!("Error: {}", e.to_string())
-    //         }
-    //       },
-    //       Error(e) => panic!("Error: {}", e.to_string())
-    //     }
-    //   },
-    //   Error(e) => panic!("Error: {}", e.to_string())
-    // }
+      Error(e) => panic!("Error when sending...\n")
+    }
     tj.join().unwrap();
   }
-}
 
+  #[test]
+  fn test_wait_deliver_timeout() {
+    let remote_addr = "sender-timeout".to_string();
+    let listener = LoopbackListener::bind(remote_addr.clone()).unwrap();
+    let connect = connect::<Loopback>(remote_addr).unwrap();
+    let mut socket = listener.accept().unwrap();
+    let isn = connect.seq();
+    // Ack the handshake ahead of time, the data is never acknowledged
+    socket.send_frame(Message::Ack {id: isn, window: 1}).unwrap();
+    let ready = connect.handshake(Duration::from_millis(10), 1).unwrap();
+    let seq = ready.seq();
+    let pending = ready.send(vec![1]).unwrap();
+    match pending.wait_deliver(Duration::from_millis(10)) {
+      (Value(ready), false) => assert_eq!(ready.seq(), seq),
+      _ => panic!("Expected timeout\n"),
+    }
+  }
+
+  #[test]
+  fn test_probe_reopens_window() {
+    let remote_addr = "sender-probe".to_string();
+    let listener = LoopbackListener::bind(remote_addr.clone()).unwrap();
+    let connect = connect::<Loopback>(remote_addr).unwrap();
+    let mut socket = listener.accept().unwrap();
+    let isn = connect.seq();
+    socket.send_frame(Message::Ack {id: isn, window: 0}).unwrap();
+    let ready = connect.handshake(Duration::from_millis(10), 1).unwrap();
+    assert_eq!(ready.rwnd(), 0);
+    // Unanswered probe, the window stays closed
+    let ready = ready.probe(Duration::from_millis(10)).unwrap();
+    assert_eq!(ready.rwnd(), 0);
+    socket.recv_frame().unwrap();
+    assert_eq!(socket.recv_frame().unwrap(), Message::Probe {id: ready.seq()});
+    socket.send_frame(Message::Ack {id: isn, window: 4}).unwrap();
+    let ready = ready.probe(Duration::from_millis(10)).unwrap();
+    assert_eq!(ready.rwnd(), 4);
+  }
+}
@@ -0,0 +1,248 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use prusti_contracts::*;
+use crate::messaging::Message;
+use crate::types::socket::SocketError;
+use crate::types::transport::Transport;
+use crate::types::{MyResult, next_seq, seq_lt};
+use super::error::Result::{self, *};
+use super::window::{Acked, Pipeline};
+use super::{Ready, SenderError::*};
+
+/// A message sent but not slid out of the window yet, with its own retransmission timer
+struct InFlight {
+  seq: u64,
+  data: Vec<u8>,
+  attempts: usize,
+  sent: Instant,
+  acked: bool,
+  rtt: Option<Duration>,
+}
+
+/// Pipelined sender state that only retransmits the messages whose ack is missing.
+/// The receiver buffers frames past a gap and confirms each of them with a Sack,
+/// so a lost frame costs one retransmission instead of the whole window (selective repeat)
+pub struct Selective<T: Transport> {
+  // Seq of the next new message
+  seq: u64,
+  socket: T,
+  capacity: usize,
+  // Ordered by seq, the front is always unacknowledged
+  outstanding: VecDeque<InFlight>,
+  rwnd: u32,
+  // Set by the sender's congestion control, capacity until then
+  cwnd: usize,
+}
+
+impl<T: Transport> Ready<T> {
+  /// Switch to selective repeat, at most capacity seqs past the oldest unacknowledged one
+  /// are in flight. Should not exceed the receiver's reorder buffer
+  #[requires(capacity > 0)]
+  #[ensures(result.noutstanding() == 0)]
+  pub fn into_selective(self, capacity: usize) -> Selective<T> {
+    Selective {seq: self.seq, socket: self.socket, capacity, outstanding: VecDeque::new(), rwnd: self.rwnd, cwnd: capacity}
+  }
+}
+
+impl<T: Transport> Selective<T> {
+  #[pure]
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  /// Take the window from an ack at or past the latest one
+  fn update_rwnd(&mut self, id: u64, window: u32) {
+    let base = match self.outstanding.front() {
+      Some(front) => front.seq,
+      None => self.seq,
+    };
+    if !seq_lt(id, base.wrapping_sub(1)) {
+      self.rwnd = window;
+    }
+  }
+
+  /// Mark every message covered by the ack, then slide the window past the acknowledged front
+  fn ack(&mut self, id: u64, cumulative: bool) -> Vec<Acked> {
+    for in_flight in self.outstanding.iter_mut() {
+      if in_flight.seq == id && !in_flight.acked && in_flight.attempts == 1 {
+        in_flight.rtt = Some(in_flight.sent.elapsed());
+      }
+      if in_flight.seq == id || (cumulative && seq_lt(in_flight.seq, id)) {
+        in_flight.acked = true;
+      }
+    }
+    let mut acked = Vec::new();
+    while self.outstanding.front().is_some_and(|front| front.acked) {
+      let front = self.outstanding.pop_front().unwrap();
+      acked.push(Acked {seq: front.seq, attempts: front.attempts, rtt: front.rtt});
+    }
+    acked
+  }
+
+  /// Earliest moment a retransmission timer runs out, None when nothing is outstanding
+  fn deadline(&self, timeout: Duration) -> Option<Instant> {
+    self.outstanding.iter()
+      .filter(|in_flight| !in_flight.acked)
+      .map(|in_flight| in_flight.sent + timeout)
+      .min()
+  }
+}
+
+impl<T: Transport> Pipeline<T> for Selective<T> {
+  #[pure]
+  fn seq(&self) -> u64 {
+    self.seq
+  }
+
+  #[pure]
+  #[trusted]
+  fn noutstanding(&self) -> usize {
+    self.outstanding.len()
+  }
+
+  #[pure]
+  fn rwnd(&self) -> u32 {
+    self.rwnd
+  }
+
+  #[pure]
+  fn limit(&self) -> usize {
+    self.capacity().min(self.cwnd).min(self.rwnd() as usize)
+  }
+
+  #[pure]
+  fn can_send(&self) -> bool {
+    self.noutstanding() < self.limit()
+  }
+
+  fn set_cwnd(&mut self, cwnd: usize) {
+    self.cwnd = cwnd;
+  }
+
+  fn send(&mut self, data: Vec<u8>) -> Result<u64> {
+    let seq = self.seq;
+    match self.socket.send_frame(Message::Data {id: seq, data: data.clone()}) {
+      MyResult::Value(_) => {},
+      MyResult::Error(SocketError::FrameTooLarge) => return Error(MessageTooLarge{seq}),
+      MyResult::Error(_) => return Error(SendError{seq}),
+    }
+    self.outstanding.push_back(InFlight {seq, data, attempts: 1, sent: Instant::now(), acked: false, rtt: None});
+    self.seq = next_seq(seq);
+    Value(seq)
+  }
+
+  /// Cumulative acks cover every seq up to theirs, Sacks a single one.
+  /// With nothing outstanding this waits up to timeout for the answer to a probe
+  fn wait_acks(&mut self, timeout: Duration) -> Result<Vec<Acked>> {
+    let t0 = Instant::now();
+    loop {
+      let deadline = self.deadline(timeout).unwrap_or(t0 + timeout);
+      let remaining = deadline.saturating_duration_since(Instant::now());
+      if remaining.as_millis() == 0 {
+        return Value(Vec::new());
+      }
+      if self.socket.set_read_timeout(remaining).is_err() {
+        return Error(BadTimeoutInput);
+      }
+      let rwnd = self.rwnd;
+      let acked = match self.socket.recv_frame() {
+        MyResult::Value(Message::Ack {id, window}) => {
+          self.update_rwnd(id, window);
+          self.ack(id, true)
+        },
+        MyResult::Value(Message::Sack {id}) => self.ack(id, false),
+        MyResult::Value(_) => Vec::new(),
+        MyResult::Error(SocketError::Timeout) => return Value(Vec::new()),
+        MyResult::Error(_) => return Error(NoResponse)
+      };
+      if !acked.is_empty() || self.rwnd != rwnd {
+        return Value(acked);
+      }
+    }
+  }
+
+  fn expired_attempts(&self, timeout: Duration) -> usize {
+    self.outstanding.iter()
+      .filter(|in_flight| !in_flight.acked && in_flight.sent.elapsed() >= timeout)
+      .map(|in_flight| in_flight.attempts)
+      .max()
+      .unwrap_or(0)
+  }
+
+  /// Only the unacknowledged messages whose own timer ran out are sent again
+  fn retransmit_expired(&mut self, timeout: Duration) -> Result<()> {
+    for in_flight in self.outstanding.iter_mut() {
+      if in_flight.acked || in_flight.sent.elapsed() < timeout {
+        continue;
+      }
+      let res = self.socket.send_frame(Message::Data {id: in_flight.seq, data: in_flight.data.clone()});
+      if res.is_err() {
+        return Error(SendError{seq: in_flight.seq});
+      }
+      in_flight.attempts += 1;
+      in_flight.sent = Instant::now();
+    }
+    Value(())
+  }
+
+  fn probe(&mut self) -> Result<()> {
+    if self.socket.send_frame(Message::Probe {id: self.seq}).is_err() {
+      return Error(SendError{seq: self.seq});
+    }
+    Value(())
+  }
+
+  fn into_ready(self) -> Ready<T> {
+    Ready {seq: self.seq, socket: self.socket, rwnd: self.rwnd}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use crate::messaging::Message;
+  use crate::types::loopback::{Loopback, LoopbackListener};
+  use crate::types::socket::SocketError;
+  use crate::types::transport::{Listener, Transport};
+  use crate::types::MyResult;
+
+  use super::super::connect;
+  use super::super::window::{Acked, Pipeline};
+
+  #[test]
+  fn test_retransmits_only_missing() {
+    let addr = "selective-repeat".to_string();
+    let listener = LoopbackListener::bind(addr.clone()).unwrap();
+    let connect = connect::<Loopback>(addr).unwrap();
+    let mut socket = listener.accept().unwrap();
+    socket.send_frame(Message::Ack {id: connect.seq(), window: 8}).unwrap();
+    let mut window = connect.handshake(Duration::from_millis(10), 1).unwrap().into_selective(3);
+    let first = window.seq();
+    for i in 0..3 {
+      window.send(vec![i]).unwrap();
+    }
+    for _ in 0..4 {
+      socket.recv_frame().unwrap();
+    }
+    // The first frame was lost, the other two were buffered by the receiver
+    socket.send_frame(Message::Sack {id: first.wrapping_add(1)}).unwrap();
+    socket.send_frame(Message::Sack {id: first.wrapping_add(2)}).unwrap();
+    assert!(window.wait_acks(Duration::from_millis(10)).unwrap().is_empty());
+    assert_eq!(window.expired_attempts(Duration::from_millis(10)), 1);
+    window.retransmit_expired(Duration::from_millis(10)).unwrap();
+    assert_eq!(socket.recv_frame().unwrap(), Message::Data {id: first, data: vec![0]});
+    socket.set_read_timeout(Duration::from_millis(10)).unwrap();
+    match socket.recv_frame() {
+      MyResult::Error(SocketError::Timeout) => {},
+      _ => panic!("Expected a single retransmission\n"),
+    }
+    socket.send_frame(Message::Ack {id: first.wrapping_add(2), window: 8}).unwrap();
+    let acked = window.wait_acks(Duration::from_secs(1)).unwrap();
+    assert_eq!(acked[0], Acked {seq: first, attempts: 2, rtt: None});
+    assert_eq!(acked.len(), 3);
+    assert!(acked[1].rtt.is_some());
+    assert_eq!(window.into_ready().seq(), first.wrapping_add(3));
+  }
+}
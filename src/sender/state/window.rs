@@ -0,0 +1,308 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use prusti_contracts::*;
+use crate::messaging::Message;
+use crate::types::socket::SocketError;
+use crate::types::transport::Transport;
+use crate::types::{MyResult, next_seq, seq_lt};
+use super::error::Result::{self, *};
+use super::{Ready, SenderError::*};
+
+/// A message sent but not acknowledged yet
+struct InFlight {
+  seq: u64,
+  data: Vec<u8>,
+  attempts: usize,
+  // Last transmission
+  sent: Instant,
+}
+
+/// Pipelined sender state, up to capacity Data frames are outstanding at once.
+/// Acks are cumulative and a single timer covers the oldest outstanding frame,
+/// when it runs out every outstanding frame is retransmitted (Go-Back-N)
+pub struct Window<T: Transport> {
+  // Seq of the next new message
+  seq: u64,
+  socket: T,
+  capacity: usize,
+  // Ordered by seq, the front is the oldest unacknowledged message
+  outstanding: VecDeque<InFlight>,
+  // Restarted whenever the window slides or goes back
+  timer: Instant,
+  rwnd: u32,
+  // Set by the sender's congestion control, capacity until then
+  cwnd: usize,
+}
+
+/// Sender state with several messages in flight, the driver fills it, waits for acks
+/// and retransmits whatever timed out
+pub trait Pipeline<T: Transport>: Sized {
+  /// Seq the next new message will be sent with
+  #[pure]
+  fn seq(&self) -> u64;
+
+  #[pure]
+  fn noutstanding(&self) -> usize;
+
+  /// Window the receiver advertised last
+  #[pure]
+  fn rwnd(&self) -> u32;
+
+  /// Frames allowed in flight, the smallest of the capacity, the congestion window
+  /// and the receiver's window
+  #[pure]
+  fn limit(&self) -> usize;
+
+  #[pure]
+  #[ensures(result == (self.noutstanding() < self.limit()))]
+  fn can_send(&self) -> bool;
+
+  /// Congestion window, frames already in flight beyond it are not recalled
+  #[ensures(self.noutstanding() == old(self.noutstanding()))]
+  fn set_cwnd(&mut self, cwnd: usize);
+
+  /// Send a new message under the next seq
+  #[requires(self.can_send())]
+  #[ensures(result.is_ok() ==> self.noutstanding() == old(self.noutstanding()) + 1)]
+  #[ensures(result.is_ok() ==> result.unwrap() == old(self.seq()))]
+  fn send(&mut self, data: Vec<u8>) -> Result<u64>;
+
+  /// Wait up to timeout for acks that slide the window and return the messages they cover
+  /// in send order. The result is empty when a retransmission timer runs out first,
+  /// or when an ack only changed the receiver's window
+  #[ensures(result.is_ok() ==> self.noutstanding() <= old(self.noutstanding()))]
+  fn wait_acks(&mut self, timeout: Duration) -> Result<Vec<Acked>>;
+
+  /// Transmissions so far of the most retransmitted message whose timer ran out, 0 if none did
+  fn expired_attempts(&self, timeout: Duration) -> usize;
+
+  /// Send again what timed out
+  #[ensures(result.is_ok() ==> self.noutstanding() == old(self.noutstanding()))]
+  fn retransmit_expired(&mut self, timeout: Duration) -> Result<()>;
+
+  /// Ask for the receiver's window while it is closed, the answer arrives through wait_acks
+  fn probe(&mut self) -> Result<()>;
+
+  /// Back to stop-and-wait once everything is acknowledged
+  #[requires(self.noutstanding() == 0)]
+  fn into_ready(self) -> Ready<T>;
+}
+
+/// Message acknowledged while the window was open
+#[derive(Clone, Debug, PartialEq)]
+pub struct Acked {
+  pub seq: u64,
+  pub attempts: usize,
+  /// Round trip measured from the ack naming this seq, only for messages
+  /// acknowledged on their first transmission (Karn)
+  pub rtt: Option<Duration>,
+}
+
+impl<T: Transport> Ready<T> {
+  /// Switch to pipelined sending, capacity 1 behaves like stop-and-wait
+  #[requires(capacity > 0)]
+  #[ensures(result.noutstanding() == 0)]
+  pub fn into_window(self, capacity: usize) -> Window<T> {
+    Window {seq: self.seq, socket: self.socket, capacity, outstanding: VecDeque::new(), timer: Instant::now(), rwnd: self.rwnd, cwnd: capacity}
+  }
+}
+
+impl<T: Transport> Window<T> {
+  #[pure]
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  /// Drop every outstanding message covered by the cumulative ack id.
+  /// The window is taken from the latest ack only, a reordered older one must not reopen it
+  fn ack(&mut self, id: u64, window: u32) -> Vec<Acked> {
+    let base = match self.outstanding.front() {
+      Some(front) => front.seq,
+      None => self.seq,
+    };
+    if !seq_lt(id, base.wrapping_sub(1)) {
+      self.rwnd = window;
+    }
+    let mut acked = Vec::new();
+    while let Some(front) = self.outstanding.front() {
+      if seq_lt(id, front.seq) {
+        break;
+      }
+      let front = self.outstanding.pop_front().unwrap();
+      let rtt = if front.seq == id && front.attempts == 1 { Some(front.sent.elapsed()) } else { None };
+      acked.push(Acked {seq: front.seq, attempts: front.attempts, rtt});
+    }
+    if !acked.is_empty() {
+      self.timer = Instant::now();
+    }
+    acked
+  }
+
+  /// Retransmit every outstanding message in order
+  #[ensures(result.is_ok() ==> self.noutstanding() == old(self.noutstanding()))]
+  pub fn go_back_n(&mut self) -> Result<()> {
+    for in_flight in self.outstanding.iter_mut() {
+      let res = self.socket.send_frame(Message::Data {id: in_flight.seq, data: in_flight.data.clone()});
+      if res.is_err() {
+        return Error(SendError{seq: in_flight.seq});
+      }
+      in_flight.attempts += 1;
+      in_flight.sent = Instant::now();
+    }
+    self.timer = Instant::now();
+    Value(())
+  }
+
+  /// Transmissions of the oldest outstanding message, 0 when nothing is outstanding
+  #[pure]
+  #[trusted]
+  pub fn oldest_attempts(&self) -> usize {
+    match self.outstanding.front() {
+      Some(front) => front.attempts,
+      None => 0,
+    }
+  }
+}
+
+impl<T: Transport> Pipeline<T> for Window<T> {
+  #[pure]
+  fn seq(&self) -> u64 {
+    self.seq
+  }
+
+  #[pure]
+  #[trusted]
+  fn noutstanding(&self) -> usize {
+    self.outstanding.len()
+  }
+
+  #[pure]
+  fn rwnd(&self) -> u32 {
+    self.rwnd
+  }
+
+  #[pure]
+  fn limit(&self) -> usize {
+    self.capacity().min(self.cwnd).min(self.rwnd() as usize)
+  }
+
+  #[pure]
+  fn can_send(&self) -> bool {
+    self.noutstanding() < self.limit()
+  }
+
+  fn set_cwnd(&mut self, cwnd: usize) {
+    self.cwnd = cwnd;
+  }
+
+  fn send(&mut self, data: Vec<u8>) -> Result<u64> {
+    let seq = self.seq;
+    match self.socket.send_frame(Message::Data {id: seq, data: data.clone()}) {
+      MyResult::Value(_) => {},
+      MyResult::Error(SocketError::FrameTooLarge) => return Error(MessageTooLarge{seq}),
+      MyResult::Error(_) => return Error(SendError{seq}),
+    }
+    if self.outstanding.is_empty() {
+      self.timer = Instant::now();
+    }
+    self.outstanding.push_back(InFlight {seq, data, attempts: 1, sent: Instant::now()});
+    self.seq = next_seq(seq);
+    Value(seq)
+  }
+
+  /// Selective acks are ignored, only the cumulative ones slide the window
+  fn wait_acks(&mut self, timeout: Duration) -> Result<Vec<Acked>> {
+    loop {
+      let remaining = timeout.saturating_sub(self.timer.elapsed());
+      if remaining.as_millis() == 0 {
+        return Value(Vec::new());
+      }
+      if self.socket.set_read_timeout(remaining).is_err() {
+        return Error(BadTimeoutInput);
+      }
+      match self.socket.recv_frame() {
+        MyResult::Value(Message::Ack {id, window}) => {
+          let rwnd = self.rwnd;
+          let acked = self.ack(id, window);
+          if !acked.is_empty() || self.rwnd != rwnd {
+            return Value(acked);
+          }
+        },
+        MyResult::Value(_) => {},
+        MyResult::Error(SocketError::Timeout) => return Value(Vec::new()),
+        MyResult::Error(_) => return Error(NoResponse)
+      }
+    }
+  }
+
+  fn expired_attempts(&self, timeout: Duration) -> usize {
+    if self.timer.elapsed() < timeout {
+      return 0;
+    }
+    self.oldest_attempts()
+  }
+
+  fn retransmit_expired(&mut self, timeout: Duration) -> Result<()> {
+    if self.timer.elapsed() < timeout {
+      return Value(());
+    }
+    self.go_back_n()
+  }
+
+  fn probe(&mut self) -> Result<()> {
+    if self.socket.send_frame(Message::Probe {id: self.seq}).is_err() {
+      return Error(SendError{seq: self.seq});
+    }
+    self.timer = Instant::now();
+    Value(())
+  }
+
+  fn into_ready(self) -> Ready<T> {
+    Ready {seq: self.seq, socket: self.socket, rwnd: self.rwnd}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use crate::messaging::Message;
+  use crate::types::loopback::{Loopback, LoopbackListener};
+  use crate::types::transport::{Listener, Transport};
+
+  use super::super::connect;
+  use super::Pipeline;
+
+  #[test]
+  fn test_cumulative_acks_and_go_back_n() {
+    let addr = "window-go-back-n".to_string();
+    let listener = LoopbackListener::bind(addr.clone()).unwrap();
+    let connect = connect::<Loopback>(addr).unwrap();
+    let mut socket = listener.accept().unwrap();
+    let isn = connect.seq();
+    socket.send_frame(Message::Ack {id: isn, window: 8}).unwrap();
+    let mut window = connect.handshake(Duration::from_millis(10), 1).unwrap().into_window(3);
+    let first = window.seq();
+    for i in 0..3 {
+      window.send(vec![i]).unwrap();
+    }
+    assert!(!window.can_send());
+    socket.recv_frame().unwrap();
+    // Only the first one is acknowledged, the other two time out and go back
+    socket.send_frame(Message::Ack {id: first, window: 8}).unwrap();
+    let acked = window.wait_acks(Duration::from_millis(10)).unwrap();
+    assert_eq!((acked[0].seq, acked[0].attempts), (first, 1));
+    assert!(acked[0].rtt.is_some());
+    assert!(window.wait_acks(Duration::from_millis(10)).unwrap().is_empty());
+    assert_eq!(window.expired_attempts(Duration::from_millis(10)), 1);
+    window.retransmit_expired(Duration::from_millis(10)).unwrap();
+    assert_eq!(window.oldest_attempts(), 2);
+    socket.send_frame(Message::Ack {id: first.wrapping_add(2), window: 8}).unwrap();
+    let acked = window.wait_acks(Duration::from_millis(10)).unwrap();
+    assert_eq!(acked.len(), 2);
+    assert!(acked.iter().all(|a| a.rtt.is_none()));
+    assert_eq!(window.noutstanding(), 0);
+    assert_eq!(window.into_ready().seq(), first.wrapping_add(3));
+  }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use self::messaging::Message;
+
+use super::*;
+use super::socket::SocketError::{self, *};
+use super::transport::{Listener, Transport};
+
+type MyResult<T> = crate::types::MyResult<T, SocketError>;
+
+/// In-process Transport over channels, frames are delivered in order and never lost.
+/// Addresses are plain names that a LoopbackListener binds, so the protocol can be
+/// exercised without real sockets
+pub struct Loopback {
+    tx: Sender<Message>,
+    rx: Receiver<Message>,
+    read_timeout: Option<Duration>,
+    sent: usize,
+    received: usize,
+}
+
+pub struct LoopbackListener {
+    name: String,
+    incoming: Receiver<Loopback>,
+}
+
+/// Bound listeners by name, connect hands the remote end of a new pair to the listener
+fn listeners() -> &'static Mutex<HashMap<String, Sender<Loopback>>> {
+    static LISTENERS: OnceLock<Mutex<HashMap<String, Sender<Loopback>>>> = OnceLock::new();
+    LISTENERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+impl Loopback {
+    /// Two connected ends
+    pub fn pair() -> (Loopback, Loopback) {
+        let (tx1, rx1) = mpsc::channel();
+        let (tx2, rx2) = mpsc::channel();
+        (Loopback::new(tx1, rx2), Loopback::new(tx2, rx1))
+    }
+
+    fn new(tx: Sender<Message>, rx: Receiver<Message>) -> Self {
+        Loopback { tx, rx, read_timeout: None, sent: 0, received: 0 }
+    }
+}
+
+impl Transport for Loopback {
+    fn connect(dest: String) -> MyResult<Self> {
+        let listeners = listeners().lock().unwrap();
+        match listeners.get(&dest) {
+            Some(incoming) => {
+                let (local, remote) = Loopback::pair();
+                match incoming.send(remote) {
+                    Ok(_) => MyResult::Value(local),
+                    Err(_) => MyResult::Error(DestinationUnreachable),
+                }
+            },
+            None => MyResult::Error(DestinationUnreachable),
+        }
+    }
+
+    fn send_frame(&mut self, msg: Message) -> MyResult<usize> {
+        let len = msg.clone().marshall().len();
+        // The peer of a real transport would reject the frame, so this one does too
+        if len > messaging::HEADER_SIZE + messaging::MAX_FRAME_SIZE {
+            return MyResult::Error(FrameTooLarge);
+        }
+        match self.tx.send(msg) {
+            Ok(_) => {
+                self.sent += 1;
+                MyResult::Value(len)
+            },
+            Err(_) => MyResult::Error(SendError),
+        }
+    }
+
+    fn recv_frame(&mut self) -> MyResult<Message> {
+        let res = match self.read_timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout),
+            None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match res {
+            Ok(msg) => {
+                self.received += 1;
+                MyResult::Value(msg)
+            },
+            Err(RecvTimeoutError::Timeout) => MyResult::Error(Timeout),
+            Err(RecvTimeoutError::Disconnected) => MyResult::Error(ConnectionClosed),
+        }
+    }
+
+    fn try_recv_frame(&mut self) -> MyResult<Message> {
+        match self.rx.try_recv() {
+            Ok(msg) => {
+                self.received += 1;
+                MyResult::Value(msg)
+            },
+            Err(TryRecvError::Empty) => MyResult::Error(Timeout),
+            Err(TryRecvError::Disconnected) => MyResult::Error(ConnectionClosed),
+        }
+    }
+
+    /// Like TcpStream, a zero timeout is rejected
+    fn set_read_timeout(&mut self, timeout: Duration) -> MyResult<()> {
+        if timeout.is_zero() {
+            return MyResult::Error(SetTimeoutFailed);
+        }
+        self.read_timeout = Some(timeout);
+        MyResult::Value(())
+    }
+
+    #[pure]
+    fn nsent(&self) -> usize {
+        self.sent
+    }
+
+    #[pure]
+    fn nrecv(&self) -> usize {
+        self.received
+    }
+}
+
+impl Listener for LoopbackListener {
+    type Transport = Loopback;
+
+    fn bind(src: String) -> MyResult<Self> {
+        let mut listeners = listeners().lock().unwrap();
+        if listeners.contains_key(&src) {
+            return MyResult::Error(BindError);
+        }
+        let (tx, incoming) = mpsc::channel();
+        listeners.insert(src.clone(), tx);
+        MyResult::Value(LoopbackListener { name: src, incoming })
+    }
+
+    fn accept(&self) -> MyResult<Loopback> {
+        match self.incoming.recv() {
+            Ok(conn) => MyResult::Value(conn),
+            Err(_) => MyResult::Error(AcceptError),
+        }
+    }
+}
+
+impl Drop for LoopbackListener {
+    /// Release the name so it can be bound again
+    fn drop(&mut self) {
+        if let Ok(mut listeners) = listeners().lock() {
+            listeners.remove(&self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::messaging::Message;
+    use crate::types::socket::SocketError;
+    use crate::types::transport::{Listener, Transport};
+    use crate::types::MyResult;
+
+    use super::{Loopback, LoopbackListener};
+
+    #[test]
+    pub fn test_connect_and_timeout() {
+        let listener = LoopbackListener::bind("loopback-test".to_string()).unwrap();
+        assert!(LoopbackListener::bind("loopback-test".to_string()).is_err());
+        let mut client = Loopback::connect("loopback-test".to_string()).unwrap();
+        let mut server = listener.accept().unwrap();
+        match server.try_recv_frame() {
+            MyResult::Error(SocketError::Timeout) => {},
+            _ => panic!("Expected no frame"),
+        }
+        client.send_frame(Message::Data { id: 1, data: vec![12] }).unwrap();
+        assert_eq!(server.try_recv_frame().unwrap(), Message::Data { id: 1, data: vec![12] });
+        client.send_frame(Message::Data { id: 1, data: vec![12] }).unwrap();
+        server.set_read_timeout(Duration::from_millis(10)).unwrap();
+        assert_eq!(server.recv_frame().unwrap(), Message::Data { id: 1, data: vec![12] });
+        match server.recv_frame() {
+            MyResult::Error(SocketError::Timeout) => {},
+            _ => panic!("Expected timeout"),
+        }
+        drop(client);
+        match server.recv_frame() {
+            MyResult::Error(SocketError::ConnectionClosed) => {},
+            _ => panic!("Expected closed connection"),
+        }
+        assert!(Loopback::connect("loopback-unbound".to_string()).is_err());
+        let mut client = Loopback::connect("loopback-test".to_string()).unwrap();
+        match client.send_frame(Message::Data { id: 2, data: vec![0; crate::messaging::MAX_FRAME_SIZE] }) {
+            MyResult::Error(SocketError::FrameTooLarge) => {},
+            _ => panic!("Expected the frame to be rejected"),
+        }
+    }
+}
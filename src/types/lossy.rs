@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use self::messaging::Message;
+
+use super::*;
+use super::socket::SocketError;
+use super::transport::{Listener, Transport};
+
+type MyResult<T> = crate::types::MyResult<T, SocketError>;
+
+/// Fault rates of the simulated fair-loss network, every rate is a probability per frame.
+/// Delays are counted in frames sent afterwards rather than in wall-clock time,
+/// so a run only depends on the seed and the order in which frames are sent
+#[derive(Clone, Debug)]
+pub struct LossConfig {
+    pub seed: u64,
+    pub drop: f64,
+    pub duplicate: f64,
+    /// The frame is overtaken by the next one
+    pub reorder: f64,
+    /// The frame is held back for up to max_delay later frames, it arrives after each of them
+    pub delay: f64,
+    pub max_delay: u32,
+}
+
+impl LossConfig {
+    /// A network that never misbehaves
+    pub fn reliable(seed: u64) -> Self {
+        LossConfig { seed, drop: 0.0, duplicate: 0.0, reorder: 0.0, delay: 0.0, max_delay: 0 }
+    }
+
+    /// True if every rate lies in [0, 1]
+    #[pure]
+    #[trusted]
+    pub fn is_valid(&self) -> bool {
+        [self.drop, self.duplicate, self.reorder, self.delay].iter().all(|rate| (0.0..=1.0).contains(rate))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum LossError {
+    /// A rate of the LossConfig is not a probability
+    InvalidConfig,
+}
+
+/// Counts of the faults injected so far
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LossStats {
+    pub dropped: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+    pub delayed: usize,
+}
+
+/// Transport wrapper that drops, duplicates, reorders and delays outgoing frames.
+/// Wrapping both ends of a link turns any reliable Transport into a fair-loss link
+pub struct Lossy<T: Transport> {
+    inner: T,
+    config: LossConfig,
+    rng: StdRng,
+    // Frames held back, with the number of sends left before they are released
+    held: Vec<(u32, Message)>,
+    stats: LossStats,
+}
+
+/// Listener whose accepted connections are Lossy
+pub struct LossyListener<L: Listener> {
+    inner: L,
+    config: LossConfig,
+    accepted: Mutex<u64>,
+}
+
+/// Configurations registered with simulate, by address
+fn networks() -> &'static Mutex<HashMap<String, (LossConfig, u64)>> {
+    static NETWORKS: OnceLock<Mutex<HashMap<String, (LossConfig, u64)>>> = OnceLock::new();
+    NETWORKS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Make every Lossy connection to addr, and every LossyListener bound to addr, follow config.
+/// The n-th connection opened to addr is seeded with config.seed + n, addresses without
+/// a configuration behave reliably. Fails with InvalidConfig unless config.is_valid()
+pub fn simulate(addr: &str, config: LossConfig) -> crate::types::MyResult<(), LossError> {
+    if !config.is_valid() {
+        return crate::types::MyResult::Error(LossError::InvalidConfig);
+    }
+    networks().lock().unwrap().insert(addr.to_string(), (config, 0));
+    crate::types::MyResult::Value(())
+}
+
+/// Configuration for the next connection to addr
+fn next_config(addr: &str) -> LossConfig {
+    let mut networks = networks().lock().unwrap();
+    match networks.get_mut(addr) {
+        Some((config, n)) => {
+            let mut next = config.clone();
+            next.seed = config.seed.wrapping_add(*n);
+            *n += 1;
+            next
+        },
+        None => LossConfig::reliable(0),
+    }
+}
+
+impl<T: Transport> Lossy<T> {
+    /// Fails with InvalidConfig unless config.is_valid()
+    pub fn new(inner: T, config: LossConfig) -> crate::types::MyResult<Self, LossError> {
+        if !config.is_valid() {
+            return crate::types::MyResult::Error(LossError::InvalidConfig);
+        }
+        crate::types::MyResult::Value(Lossy::wrap(inner, config))
+    }
+
+    // Configurations registered with simulate were validated there
+    #[requires(config.is_valid())]
+    fn wrap(inner: T, config: LossConfig) -> Self {
+        let rng = StdRng::seed_from_u64(config.seed);
+        Lossy { inner, config, rng, held: Vec::new(), stats: LossStats::default() }
+    }
+
+    pub fn stats(&self) -> &LossStats {
+        &self.stats
+    }
+
+    /// Count every held frame down by one send and take out those whose delay has run out, in the order they were held
+    fn due(&mut self) -> Vec<Message> {
+        let mut due = Vec::new();
+        let mut i = 0;
+        while i < self.held.len() {
+            self.held[i].0 -= 1;
+            if self.held[i].0 == 0 {
+                due.push(self.held.remove(i).1);
+            } else {
+                i += 1;
+            }
+        }
+        due
+    }
+
+    /// Send frames that were held back
+    fn release(&mut self, frames: Vec<Message>) -> MyResult<()> {
+        for msg in frames {
+            let res = self.inner.send_frame(msg);
+            if res.is_err() {
+                return MyResult::Error(res.unwrap_err());
+            }
+        }
+        MyResult::Value(())
+    }
+
+    /// Apply the drawn faults to msg, sending it unless it is dropped or held back
+    fn pass(&mut self, msg: Message, faults: (bool, bool, bool, bool, u32)) -> MyResult<()> {
+        let (drop, duplicate, reorder, delay, distance) = faults;
+        if drop {
+            self.stats.dropped += 1;
+            return MyResult::Value(());
+        }
+        if duplicate {
+            self.stats.duplicated += 1;
+            let res = self.inner.send_frame(msg.clone());
+            if res.is_err() {
+                return MyResult::Error(res.unwrap_err());
+            }
+        }
+        if reorder {
+            self.stats.reordered += 1;
+            self.held.push((1, msg));
+            return MyResult::Value(());
+        }
+        if delay && self.config.max_delay > 0 {
+            self.stats.delayed += 1;
+            self.held.push((distance, msg));
+            return MyResult::Value(());
+        }
+        match self.inner.send_frame(msg) {
+            MyResult::Value(_) => MyResult::Value(()),
+            MyResult::Error(e) => MyResult::Error(e),
+        }
+    }
+}
+
+/// Frames still held back leave when the transport is closed, like frames still in flight
+impl<T: Transport> Drop for Lossy<T> {
+    fn drop(&mut self) {
+        let held: Vec<Message> = self.held.drain(..).map(|(_, msg)| msg).collect();
+        let _ = self.release(held);
+    }
+}
+
+impl<T: Transport> Transport for Lossy<T> {
+    fn connect(dest: String) -> MyResult<Self> {
+        let config = next_config(&dest);
+        match T::connect(dest) {
+            MyResult::Value(inner) => MyResult::Value(Lossy::wrap(inner, config)),
+            MyResult::Error(e) => MyResult::Error(e),
+        }
+    }
+
+    /// Fault decisions draw the same number of values for every frame,
+    /// so the n-th frame always meets the same fate for a given seed
+    fn send_frame(&mut self, msg: Message) -> MyResult<usize> {
+        let len = msg.clone().marshall().len();
+        let drop = self.rng.gen_bool(self.config.drop);
+        let duplicate = self.rng.gen_bool(self.config.duplicate);
+        let reorder = self.rng.gen_bool(self.config.reorder);
+        let delay = self.rng.gen_bool(self.config.delay);
+        let distance = self.rng.gen_range(1..self.config.max_delay.max(1) + 1);
+        // Frames held before this one leave after it, so that this one overtakes them.
+        // A frame held now is only counted down by later sends
+        let due = self.due();
+        let res = self.pass(msg, (drop, duplicate, reorder, delay, distance));
+        if res.is_err() {
+            return MyResult::Error(res.unwrap_err());
+        }
+        match self.release(due) {
+            MyResult::Value(_) => MyResult::Value(len),
+            MyResult::Error(e) => MyResult::Error(e),
+        }
+    }
+
+    fn recv_frame(&mut self) -> MyResult<Message> {
+        self.inner.recv_frame()
+    }
+
+    fn try_recv_frame(&mut self) -> MyResult<Message> {
+        self.inner.try_recv_frame()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> MyResult<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    #[pure]
+    fn nsent(&self) -> usize {
+        self.inner.nsent()
+    }
+
+    #[pure]
+    fn nrecv(&self) -> usize {
+        self.inner.nrecv()
+    }
+}
+
+impl<L: Listener> Listener for LossyListener<L> {
+    type Transport = Lossy<L::Transport>;
+
+    fn bind(src: String) -> MyResult<Self> {
+        let config = next_config(&src);
+        match L::bind(src) {
+            MyResult::Value(inner) => MyResult::Value(LossyListener { inner, config, accepted: Mutex::new(0) }),
+            MyResult::Error(e) => MyResult::Error(e),
+        }
+    }
+
+    /// The n-th accepted connection is seeded with the listener's seed + n
+    fn accept(&self) -> MyResult<Self::Transport> {
+        match self.inner.accept() {
+            MyResult::Value(inner) => {
+                let mut accepted = self.accepted.lock().unwrap();
+                let mut config = self.config.clone();
+                config.seed = config.seed.wrapping_add(*accepted);
+                *accepted += 1;
+                MyResult::Value(Lossy::wrap(inner, config))
+            },
+            MyResult::Error(e) => MyResult::Error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use crate::messaging::Message;
+    use crate::receiver::PerfectLinkReceiver;
+    use crate::sender::{PerfectLinkSender, Repeat, RetryPolicy};
+    use crate::types::loopback::{Loopback, LoopbackListener};
+    use crate::types::transport::Transport;
+    use crate::types::MyResult;
+    use crate::Link;
+
+    use super::{simulate, LossConfig, Lossy, LossyListener};
+
+    fn faulty(seed: u64) -> LossConfig {
+        LossConfig { seed, drop: 0.3, duplicate: 0.2, reorder: 0.2, delay: 0.1, max_delay: 3 }
+    }
+
+    /// Ids of the frames that make it through n sends
+    fn run(config: LossConfig, n: u64) -> Vec<u64> {
+        let (client, mut server) = Loopback::pair();
+        let mut client = Lossy::new(client, config).unwrap();
+        for id in 0..n {
+            client.send_frame(Message::Ack { id, window: 0 }).unwrap();
+        }
+        drop(client);
+        let mut ids = Vec::new();
+        loop {
+            match server.recv_frame() {
+                MyResult::Value(msg) => ids.push(msg.id()),
+                MyResult::Error(_) => return ids,
+            }
+        }
+    }
+
+    #[test]
+    fn test_same_seed_same_faults() {
+        let first = run(faulty(7), 200);
+        assert_eq!(first, run(faulty(7), 200));
+        assert_ne!(first, run(faulty(8), 200));
+        assert_ne!(first, (0..200).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_reordered_frames_arrive_late() {
+        let reordering = LossConfig { reorder: 0.5, ..LossConfig::reliable(3) };
+        let delaying = LossConfig { delay: 0.5, max_delay: 3, ..LossConfig::reliable(3) };
+        for config in [reordering, delaying] {
+            let ids = run(config, 200);
+            // Frames still held when the transport closed are flushed, nothing is lost
+            assert_eq!(ids.len(), 200);
+            assert!(ids.windows(2).any(|pair| pair[0] > pair[1]));
+        }
+    }
+
+    #[test]
+    fn test_rates_must_be_probabilities() {
+        for config in [LossConfig { drop: 1.5, ..faulty(1) }, LossConfig { delay: -0.1, ..faulty(1) }, LossConfig { reorder: f64::NAN, ..faulty(1) }] {
+            assert!(!config.is_valid());
+            assert!(simulate("lossy-invalid", config.clone()).is_err());
+            assert!(Lossy::new(Loopback::pair().0, config).is_err());
+        }
+        assert!(LossConfig { drop: 1.0, duplicate: 0.0, ..faulty(1) }.is_valid());
+    }
+
+    #[test]
+    fn test_perfect_link_over_fair_loss() {
+        let addr = "lossy-perfect-link";
+        simulate(addr, faulty(42)).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let mut receiver: PerfectLinkReceiver<LossyListener<LoopbackListener>> = PerfectLinkReceiver::bind(addr.to_string()).unwrap();
+        thread::spawn(move || {
+            receiver.run(|_, data| tx.send(data.to_vec()).unwrap(), |_| {});
+        });
+        let mut sender: PerfectLinkSender<Lossy<Loopback>> = PerfectLinkSender::new(addr.to_string(), RetryPolicy::forever(Duration::from_millis(20)));
+        for i in 0..30u8 {
+            sender.send(vec![i]).unwrap();
+        }
+        for i in 0..30u8 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec![i]);
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    /// Send 40 messages in one batch with 8 in flight and check they arrive once and in order
+    fn run_batch(addr: &str, repeat: Repeat) {
+        let (tx, rx) = mpsc::channel();
+        let mut receiver: PerfectLinkReceiver<LossyListener<LoopbackListener>> = PerfectLinkReceiver::bind(addr.to_string()).unwrap();
+        thread::spawn(move || {
+            receiver.run(|_, data| tx.send(data[0]).unwrap(), |_| {});
+        });
+        let link = Link { src: format!("{}-src", addr), dst: addr.to_string(), capacity: 8 };
+        let mut sender: PerfectLinkSender<Lossy<Loopback>> = PerfectLinkSender::from_link(&link, RetryPolicy::forever(Duration::from_millis(20)))
+            .with_repeat(repeat);
+        let receipts = sender.send_batch((0..40u8).map(|i| vec![i]).collect()).unwrap();
+        assert_eq!(receipts.len(), 40);
+        assert_eq!(sender.ndelivered(), 40);
+        for i in 0..40u8 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), i);
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_go_back_n_over_fair_loss() {
+        let addr = "lossy-go-back-n";
+        simulate(addr, faulty(9)).unwrap();
+        run_batch(addr, Repeat::GoBackN);
+    }
+
+    #[test]
+    fn test_selective_repeat_over_fair_loss() {
+        let addr = "lossy-selective-repeat";
+        simulate(addr, faulty(11)).unwrap();
+        run_batch(addr, Repeat::Selective);
+    }
+}
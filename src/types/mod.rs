@@ -1,9 +1,15 @@
 use std::fmt::{Debug, Display};
 
 use super::*;
+use self::messaging::Message;
 
 pub mod array;
+pub mod loopback;
+pub mod lossy;
 pub mod socket;
+pub mod storage;
+pub mod transport;
+pub mod udp;
 
 // trait ToString {
 //     fn to_string(&self) -> String;
@@ -49,31 +55,70 @@ where E: Clone {
     }
 }
 
-pub struct Packet {
-    seq: u8,
-    data: u8,
+/// Sequence numbers live in a u64 space and wrap around after u64::MAX,
+/// so they are advanced with next_seq and ordered with serial number arithmetic (RFC 1982)
+#[pure]
+#[trusted]
+#[ensures(seq < u64::MAX ==> result == seq + 1)]
+#[ensures(seq == u64::MAX ==> result == 0)]
+pub fn next_seq(seq: u64) -> u64 {
+    seq.wrapping_add(1)
 }
 
-pub const BYTES_PER_PACKET: usize = 2;
+/// True if a comes strictly before b in serial number order
+#[pure]
+#[trusted]
+#[ensures(a == b ==> !result)]
+pub fn seq_lt(a: u64, b: u64) -> bool {
+    a != b && b.wrapping_sub(a) < (1 << 63)
+}
+
+pub struct Packet {
+    seq: u64,
+    data: Vec<u8>,
+}
 
 impl Packet {
-    pub fn new(seq: u8, data: u8) -> Self {
+    pub fn new(seq: u64, data: Vec<u8>) -> Self {
         Packet { seq, data }
     }
 
-    pub fn marshall(&self) -> [u8; 2] {
-        [self.seq, self.data]
+    /// Packets travel as Message::Data frames
+    pub fn marshall(&self) -> Vec<u8> {
+        Message::Data { id: self.seq, data: self.data.clone() }.marshall()
     }
 
-    pub fn unmarshall(data: [u8; 2]) -> Self {
-        Packet { seq: data[0], data: data[1] }
+    pub fn unmarshall(buf: &[u8]) -> Option<Self> {
+        match Message::unmarshall(buf) {
+            Some(Message::Data { id, data }) => Some(Packet { seq: id, data }),
+            _ => None,
+        }
     }
 
-    pub fn seq(&self) -> u8 {
+    pub fn seq(&self) -> u64 {
         self.seq
     }
 
-    pub fn data(&self) -> u8 {
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
         self.data
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_seq, seq_lt};
+
+    #[test]
+    fn test_seq_wraparound() {
+        assert_eq!(next_seq(254), 255);
+        assert_eq!(next_seq(u64::MAX), 0);
+        assert!(seq_lt(u64::MAX, 0));
+        assert!(seq_lt(u64::MAX - 10, 5));
+        assert!(!seq_lt(5, u64::MAX - 10));
+        assert!(!seq_lt(7, 7));
+    }
+}
@@ -4,11 +4,25 @@ use self::messaging::Message;
 
 use super::{*};
 use super::array::Array;
+use super::transport::{Listener, Transport};
 
 pub struct Socket {
     stream: TcpStream,
-    sent: Array<u8>,
-    received: Array<u8>,
+    decoder: FrameDecoder,
+    sent: Array<u64>,
+    received: Array<u64>,
+}
+
+/// Size of the chunks read from the stream into the decoder
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Collects bytes across reads and splits them into complete frames.
+/// TCP may split a frame over several reads or merge several frames into one,
+/// so incomplete bytes are kept until the rest of the frame arrives
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    /// Set once a length prefix was out of range, the frame boundaries of the stream are lost
+    broken: bool,
 }
 
 type MyResult<T> = crate::types::MyResult<T, SocketError>;
@@ -22,44 +36,192 @@ pub enum SocketError {
     DestinationUnreachable,
     SetTimeoutFailed,
     BindError,
-    AcceptError
+    AcceptError,
+    MalformedFrame,
+    ConnectionClosed,
+    FrameTooLarge,
 }
 
 use SocketError::*;
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder { buf: Vec::new(), broken: false }
+    }
+
+    /// Bytes fed to a broken decoder are dropped
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if !self.broken {
+            self.buf.extend_from_slice(bytes);
+        }
+    }
+
+    /// Number of buffered bytes not taken out as frames yet, complete frames included
+    #[pure]
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Take the next complete frame out of the buffer, length prefix included.
+    /// Returns None until enough bytes have been fed. An out of range length prefix breaks
+    /// the decoder for good: the buffer is dropped and every later call is a MalformedFrame
+    pub fn next_frame(&mut self) -> MyResult<Option<Vec<u8>>> {
+        if self.broken {
+            return MyResult::Error(MalformedFrame);
+        }
+        if self.buf.len() < messaging::HEADER_SIZE {
+            return MyResult::Value(None);
+        }
+        let len = match Message::frame_len(&self.buf) {
+            Some(len) => len,
+            None => {
+                self.buf.clear();
+                self.broken = true;
+                return MyResult::Error(MalformedFrame);
+            },
+        };
+        if self.buf.len() < len {
+            return MyResult::Value(None);
+        }
+        let frame: Vec<u8> = self.buf.drain(..len).collect();
+        MyResult::Value(Some(frame))
+    }
+
+    pub fn next_message(&mut self) -> MyResult<Option<Message>> {
+        let frame = self.next_frame();
+        if frame.is_err() {
+            return MyResult::Error(frame.unwrap_err());
+        }
+        match frame.unwrap() {
+            Some(frame) => match Message::unmarshall(&frame) {
+                Some(msg) => MyResult::Value(Some(msg)),
+                None => MyResult::Error(MalformedFrame),
+            },
+            None => MyResult::Value(None),
+        }
+    }
+}
+
 impl Socket {
+    fn new(stream: TcpStream) -> Self {
+        Socket { stream, decoder: FrameDecoder::new(), sent: Array::new(), received: Array::new() }
+    }
+
     #[ensures(result.is_ok() ==> result.unwrap().sent.len() == 0)]
     #[ensures(result.is_ok() ==> result.unwrap().received.len() == 0)]
     pub fn connect(dest: String) -> MyResult<Socket> {
         let stream = TcpStream::connect(dest);
         match stream {
-            Ok(stream) => MyResult::Value(Socket { stream, sent: Array::new(), received: Array::new()}),
+            Ok(stream) => MyResult::Value(Socket::new(stream)),
             Err(e) => MyResult::Error(DestinationUnreachable),
         }
     }
 
     pub fn send_msg(&mut self, pkt: Packet) -> MyResult<()> {
-        let res = self.stream.write(&pkt.marshall());
+        let res = self.stream.write_all(&pkt.marshall());
         match res {
-            Ok(BYTES_PER_PACKET) => MyResult::Value(()),
-            _ => MyResult::Error(SendError), 
+            Ok(_) => MyResult::Value(()),
+            Err(e) => MyResult::Error(SendError),
         }
     }
 
-    pub fn recv_msg(&mut self, pkt: Packet) -> MyResult<Packet> {
-        let mut buffer = [0; 2];
-        let result = self.stream.read(&mut buffer);
+    pub fn recv_msg(&mut self) -> MyResult<Packet> {
+        let frame = self.read_frame();
+        if frame.is_err() {
+            return MyResult::Error(frame.unwrap_err());
+        }
+        match Packet::unmarshall(&frame.unwrap()) {
+            Some(pkt) => MyResult::Value(pkt),
+            None => MyResult::Error(RecvError),
+        }
+    }
+
+    /// Send a whole message as a single length-prefixed frame
+    #[ensures(result.is_ok() ==> self.sent.len() == old(self.sent.len()) + 1)]
+    #[ensures(self.received.len() == old(self.received.len()))]
+    #[ensures(!result.is_ok() ==> self.sent.len() == old(self.sent.len()))]
+    pub fn send_frame(&mut self, msg: Message) -> MyResult<usize> {
+        let id = msg.id();
+        let buf = msg.marshall();
+        if buf.len() > messaging::HEADER_SIZE + messaging::MAX_FRAME_SIZE {
+            return MyResult::Error(FrameTooLarge);
+        }
+        let result = self.stream.write_all(&buf);
         match result {
-            Ok(n) => {
-                if n == 0 {
-                    MyResult::Error(Timeout)
-                } else if n == BYTES_PER_PACKET {
-                    let pkt = Packet::unmarshall(buffer);
-                    MyResult::Value(pkt)
+            Ok(_) => {
+                if self.sent.push(id).is_ok() {
+                    MyResult::Value(buf.len())
+                } else {
+                    MyResult::Error(BufferFull)
+                }
+            },
+            Err(e) => MyResult::Error(SendError),
+        }
+    }
+
+    /// Receive a whole frame and decode it, MalformedFrame if it does not decode
+    #[ensures(result.is_ok() ==> self.received.len() == old(self.received.len()) + 1)]
+    #[ensures(!result.is_ok() ==> self.received.len() == old(self.received.len()))]
+    pub fn recv_frame(&mut self) -> MyResult<Message> {
+        let frame = self.read_frame();
+        if frame.is_err() {
+            return MyResult::Error(frame.unwrap_err());
+        }
+        match Message::unmarshall(&frame.unwrap()) {
+            Some(msg) => {
+                if self.received.push(msg.id()).is_ok() {
+                    MyResult::Value(msg)
                 } else {
-                    MyResult::Error(RecvError)
+                    MyResult::Error(BufferFull)
                 }
             },
-            Err(e) => MyResult::Error(RecvError),
+            // A corrupt peer, unlike a dead one, is not a RecvError
+            None => MyResult::Error(MalformedFrame),
+        }
+    }
+
+    /// Take a frame that already arrived, in the decoder or in the stream, without waiting.
+    /// Timeout if there is none
+    #[ensures(result.is_ok() ==> self.received.len() == old(self.received.len()) + 1)]
+    #[ensures(!result.is_ok() ==> self.received.len() == old(self.received.len()))]
+    pub fn try_recv_frame(&mut self) -> MyResult<Message> {
+        if self.stream.set_nonblocking(true).is_err() {
+            return MyResult::Error(SetTimeoutFailed);
+        }
+        let res = self.recv_frame();
+        if self.stream.set_nonblocking(false).is_err() {
+            return MyResult::Error(SetTimeoutFailed);
+        }
+        res
+    }
+
+    /// Read from the stream until the decoder holds a complete frame.
+    /// Bytes of a partially received frame stay in the decoder across timeouts,
+    /// and frames that arrived in the same read are served by later calls
+    #[trusted]
+    fn read_frame(&mut self) -> MyResult<Vec<u8>> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            let frame = self.decoder.next_frame();
+            if frame.is_err() {
+                return MyResult::Error(frame.unwrap_err());
+            }
+            if let Some(frame) = frame.unwrap() {
+                return MyResult::Value(frame);
+            }
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return MyResult::Error(ConnectionClosed),
+                Ok(n) => self.decoder.feed(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return MyResult::Error(Socket::read_error(e)),
+            }
+        }
+    }
+
+    fn read_error(e: io::Error) -> SocketError {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Timeout,
+            _ => RecvError,
         }
     }
 
@@ -70,9 +232,10 @@ impl Socket {
         let result = self.stream.write(&[data]);
         match result {
             Ok(n) => {
-                match self.sent.push(data) {
-                    MyResult::Value(_) => MyResult::Value(n),
-                    MyResult::Error(_) => MyResult::Error(BufferFull),
+                if self.sent.push(u64::from(data)).is_ok() {
+                    MyResult::Value(n)
+                } else {
+                    MyResult::Error(BufferFull)
                 }
             },
             Err(e) => MyResult::Error(SendError),
@@ -89,14 +252,15 @@ impl Socket {
         match result {
             Ok(n) => {
                 if n == 0 {
-                    return MyResult::Error(Timeout);
+                    return MyResult::Error(ConnectionClosed);
                 }
-                match self.received.push(buffer[0]) {
-                    MyResult::Value(_) => MyResult::Value(buffer[0]),
-                    MyResult::Error(_) => MyResult::Error(BufferFull),
+                if self.received.push(u64::from(buffer[0])).is_ok() {
+                    MyResult::Value(buffer[0])
+                } else {
+                    MyResult::Error(BufferFull)
                 }
             },
-            Err(e) => MyResult::Error(RecvError),
+            Err(e) => MyResult::Error(Socket::read_error(e)),
         }
     }
 
@@ -149,7 +313,7 @@ impl ServerSocket {
         let stream = self.listener.accept();
         match stream {
             Ok((stream, _)) => {
-                let s = Socket { stream, sent: Array::new(), received: Array::new()};
+                let s = Socket::new(stream);
                 if self.read_timeout.is_some() {
                     let err = s.set_read_timeout(self.read_timeout.unwrap());
                     if err.is_err() {
@@ -168,6 +332,14 @@ impl ServerSocket {
         }
     }
 
+    /// Address the listener is bound to, useful after binding port 0
+    pub fn local_addr(&self) -> MyResult<String> {
+        match self.listener.local_addr() {
+            Ok(addr) => MyResult::Value(addr.to_string()),
+            Err(e) => MyResult::Error(BindError),
+        }
+    }
+
     pub fn set_read_timeout(&mut self, timeout: Duration) -> &ServerSocket {
         self.read_timeout = Some(timeout);
         self
@@ -180,52 +352,158 @@ impl ServerSocket {
 
 }
 
+impl Transport for Socket {
+    fn connect(dest: String) -> MyResult<Self> {
+        Socket::connect(dest)
+    }
+
+    fn send_frame(&mut self, msg: Message) -> MyResult<usize> {
+        Socket::send_frame(self, msg)
+    }
+
+    fn recv_frame(&mut self) -> MyResult<Message> {
+        Socket::recv_frame(self)
+    }
+
+    fn try_recv_frame(&mut self) -> MyResult<Message> {
+        Socket::try_recv_frame(self)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> MyResult<()> {
+        Socket::set_read_timeout(self, timeout)
+    }
+
+    #[pure]
+    fn nsent(&self) -> usize {
+        Socket::nsent(self)
+    }
+
+    #[pure]
+    fn nrecv(&self) -> usize {
+        Socket::nrecv(self)
+    }
+}
+
+impl Listener for ServerSocket {
+    type Transport = Socket;
+
+    fn bind(src: String) -> MyResult<Self> {
+        ServerSocket::bind(src)
+    }
+
+    fn accept(&self) -> MyResult<Socket> {
+        ServerSocket::accept(self)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::io::Write;
     use std::{thread, time::Duration};
 
+    use crate::messaging::Message;
     use crate::types::{socket::Socket, MyResult};
 
-    use super::ServerSocket;
+    use super::{FrameDecoder, ServerSocket, SocketError};
+
+    #[test]
+    pub fn test_decoder_partial_reads() {
+        let msg = Message::Data { id: 4, data: vec![9; 1000] };
+        let buf = msg.clone().marshall();
+        let mut decoder = FrameDecoder::new();
+        for b in &buf[..buf.len() - 1] {
+            decoder.feed(&[*b]);
+            assert!(decoder.next_message().unwrap().is_none());
+        }
+        decoder.feed(&buf[buf.len() - 1..]);
+        assert_eq!(decoder.next_message().unwrap(), Some(msg));
+        assert_eq!(decoder.pending(), 0);
+    }
+
+    #[test]
+    pub fn test_decoder_coalesced_frames() {
+        let first = Message::Data { id: 1, data: vec![1, 2, 3] };
+        let second = Message::Ack { id: 1, window: 1 };
+        let third = Message::Data { id: 2, data: vec![] };
+        let mut buf = first.clone().marshall();
+        buf.extend(second.clone().marshall());
+        let third_buf = third.clone().marshall();
+        buf.extend_from_slice(&third_buf[..3]);
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&buf);
+        assert_eq!(decoder.next_message().unwrap(), Some(first));
+        assert_eq!(decoder.next_message().unwrap(), Some(second));
+        assert!(decoder.next_message().unwrap().is_none());
+        decoder.feed(&third_buf[3..]);
+        assert_eq!(decoder.next_message().unwrap(), Some(third));
+    }
+
+    #[test]
+    pub fn test_decoder_oversized_frame() {
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&u32::MAX.to_be_bytes());
+        assert!(decoder.next_frame().is_err());
+        assert_eq!(decoder.pending(), 0);
+        // The stream cannot be resynchronised, even a well-formed frame is refused
+        decoder.feed(&Message::Probe { id: 1 }.marshall());
+        assert_eq!(decoder.pending(), 0);
+        assert!(decoder.next_frame().is_err());
+    }
 
     #[test]
     pub fn test_timeout() {
-        let tr = thread::spawn(|| {
-            run_server()
-        });
-        Socket::connect("localhost:8080".to_string()).unwrap()
-            .send(12).unwrap();
-        tr.join().unwrap();
-    }
-
-    pub fn run_server() {
-        let mut s = ServerSocket::bind("localhost:8080".to_string()).unwrap()
-            .accept().unwrap();
-        thread::sleep(Duration::from_secs(2));
-        s.set_read_timeout(Duration::from_secs(3));
-        let r = s.recv();
-        match r {
-            MyResult::MyResult::Value(v) => {
-                print!("Received: {}\n", v);
-                print!("Server done")
-            },
-            MyResult::MyResult::Error(e) => print!("Error: {:?}\n", e),
+        let server = ServerSocket::bind("localhost:0".to_string()).unwrap();
+        let mut client = Socket::connect(server.local_addr().unwrap()).unwrap();
+        client.send(12).unwrap();
+        let mut s = server.accept().unwrap();
+        s.set_read_timeout(Duration::from_millis(100)).unwrap();
+        match s.recv() {
+            MyResult::Value(v) => assert_eq!(v, 12),
+            MyResult::Error(e) => panic!("Error: {:?}\n", e),
         }
-        let r = s.recv();
-        match r {
-            MyResult::MyResult::Value(v) => {
-                print!("Received: {}\n", v);
-                print!("Server done\n")
-            },
-            MyResult::MyResult::Error(e) => print!("Error: {:?}\n", e),
+        match s.recv() {
+            MyResult::Error(SocketError::Timeout) => {},
+            _ => panic!("Expected timeout\n"),
         }
-        
     }
 
-    pub fn run_client() {
-        let mut client = Socket::connect("localhost:8080".to_string()).unwrap();
-        // let r = client.send(1).unwrap();
-        // print!("Sent: {}\n", r);
-        // print!("Client done");
+    #[test]
+    pub fn test_frames() {
+        let server = ServerSocket::bind("localhost:0".to_string()).unwrap();
+        let mut client = Socket::connect(server.local_addr().unwrap()).unwrap();
+        let mut s = server.accept().unwrap();
+        let msg = Message::Data { id: 1, data: vec![7; 100_000] };
+        client.send_frame(msg.clone()).unwrap();
+        client.send_frame(Message::Ack { id: 2, window: 1 }).unwrap();
+        assert_eq!(s.recv_frame().unwrap(), msg);
+        assert_eq!(s.recv_frame().unwrap(), Message::Ack { id: 2, window: 1 });
+        assert_eq!(s.nrecv(), 2);
+        match client.send_frame(Message::Data { id: 3, data: vec![0; crate::messaging::MAX_FRAME_SIZE] }) {
+            MyResult::Error(SocketError::FrameTooLarge) => {},
+            _ => panic!("Expected the frame to be rejected\n"),
+        }
+        assert_eq!(client.nsent(), 2);
+        match s.try_recv_frame() {
+            MyResult::Error(SocketError::Timeout) => {},
+            _ => panic!("Expected no frame\n"),
+        }
+        client.send_frame(Message::Probe { id: 4 }).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(s.try_recv_frame().unwrap(), Message::Probe { id: 4 });
+        // Blocking reads work again afterwards
+        client.send_frame(Message::Probe { id: 5 }).unwrap();
+        assert_eq!(s.recv_frame().unwrap(), Message::Probe { id: 5 });
+        // A frame that is complete but does not decode is the same error as in the decoder
+        client.stream.write_all(&[0, 0, 0, 1, 0xff]).unwrap();
+        match s.recv_frame() {
+            MyResult::Error(SocketError::MalformedFrame) => {},
+            _ => panic!("Expected a malformed frame\n"),
+        }
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&[0, 0, 0, 1, 0xff]);
+        match decoder.next_message() {
+            MyResult::Error(SocketError::MalformedFrame) => {},
+            _ => panic!("Expected a malformed frame\n"),
+        }
     }
-}
\ No newline at end of file
+}
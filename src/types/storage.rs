@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::*;
+
+type MyResult<T> = crate::types::MyResult<T, StorageError>;
+
+#[derive(Clone, Debug)]
+pub enum StorageError {
+    /// Keys name files, they are non-empty and hold no path separator nor a leading dot
+    InvalidKey,
+    WriteError,
+    ReadError,
+}
+
+/// Stable storage of the crash-recovery model: whatever store wrote survives a crash of the process.
+/// A store either replaces the whole value of the key or leaves the previous one, never a mix of both
+pub trait Storage {
+    /// True if key holds value, the model the specs of store and retrieve are written against
+    #[pure]
+    fn holds(&self, key: &str, value: &[u8]) -> bool;
+
+    /// True if something was stored under key
+    #[pure]
+    fn contains(&self, key: &str) -> bool;
+
+    #[ensures(result.is_ok() ==> self.contains(key))]
+    #[ensures(result.is_ok() ==> self.holds(key, value))]
+    fn store(&mut self, key: &str, value: &[u8]) -> MyResult<()>;
+
+    /// The value last stored under key, None if there is none
+    #[ensures(result.is_ok() ==> result.unwrap().is_some() == self.contains(key))]
+    #[ensures(result.is_ok() && self.contains(key) ==> self.holds(key, &result.unwrap().unwrap()))]
+    fn retrieve(&self, key: &str) -> MyResult<Option<Vec<u8>>>;
+}
+
+#[pure]
+#[trusted]
+fn valid_key(key: &str) -> bool {
+    !key.is_empty() && !key.starts_with('.') && !key.contains(['/', '\\'])
+}
+
+/// Replace the file at path with buf so that a crash leaves either the old or the new content:
+/// buf is written to a temporary file next to it, fsync'd, then renamed over path,
+/// and the directory is fsync'd so the rename survives as well
+pub fn write_atomically(path: &Path, buf: &[u8]) -> io::Result<()> {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+    };
+    // Keys of a FileStorage never start with a dot, so the temporary file cannot be another key's
+    let tmp = path.with_file_name(format!(".{}.tmp", name));
+    let mut file = File::create(&tmp)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Storage in a directory, one file per key, every value is written with write_atomically
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    /// Store under dir, created if needed. Values stored by an earlier process are retrieved again
+    pub fn open<P: AsRef<Path>>(dir: P) -> MyResult<FileStorage> {
+        match fs::create_dir_all(dir.as_ref()) {
+            Ok(_) => MyResult::Value(FileStorage { dir: dir.as_ref().to_path_buf() }),
+            Err(_) => MyResult::Error(StorageError::WriteError),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Storage for FileStorage {
+    #[pure]
+    #[trusted]
+    fn holds(&self, key: &str, value: &[u8]) -> bool {
+        valid_key(key) && fs::read(self.dir.join(key)).is_ok_and(|stored| stored == value)
+    }
+
+    #[pure]
+    #[trusted]
+    fn contains(&self, key: &str) -> bool {
+        valid_key(key) && self.dir.join(key).is_file()
+    }
+
+    #[trusted]
+    fn store(&mut self, key: &str, value: &[u8]) -> MyResult<()> {
+        if !valid_key(key) {
+            return MyResult::Error(StorageError::InvalidKey);
+        }
+        match write_atomically(&self.dir.join(key), value) {
+            Ok(_) => MyResult::Value(()),
+            Err(_) => MyResult::Error(StorageError::WriteError),
+        }
+    }
+
+    #[trusted]
+    fn retrieve(&self, key: &str) -> MyResult<Option<Vec<u8>>> {
+        if !valid_key(key) {
+            return MyResult::Error(StorageError::InvalidKey);
+        }
+        match fs::read(self.dir.join(key)) {
+            Ok(value) => MyResult::Value(Some(value)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => MyResult::Value(None),
+            Err(_) => MyResult::Error(StorageError::ReadError),
+        }
+    }
+}
+
+/// Storage that lives as long as the value, for tests and simulated crashes:
+/// keep it and hand it to the restarted process, drop it to lose everything
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    values: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage { values: HashMap::new() }
+    }
+}
+
+impl Storage for MemoryStorage {
+    #[pure]
+    #[trusted]
+    fn holds(&self, key: &str, value: &[u8]) -> bool {
+        self.values.get(key).is_some_and(|stored| stored.as_slice() == value)
+    }
+
+    #[pure]
+    #[trusted]
+    fn contains(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    #[trusted]
+    fn store(&mut self, key: &str, value: &[u8]) -> MyResult<()> {
+        if !valid_key(key) {
+            return MyResult::Error(StorageError::InvalidKey);
+        }
+        self.values.insert(key.to_string(), value.to_vec());
+        MyResult::Value(())
+    }
+
+    #[trusted]
+    fn retrieve(&self, key: &str) -> MyResult<Option<Vec<u8>>> {
+        if !valid_key(key) {
+            return MyResult::Error(StorageError::InvalidKey);
+        }
+        MyResult::Value(self.values.get(key).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{FileStorage, MemoryStorage, Storage, StorageError};
+    use crate::types::MyResult;
+
+    fn check_store_then_retrieve<S: Storage>(storage: &mut S) {
+        assert_eq!(storage.retrieve("state").unwrap(), None);
+        storage.store("state", &[1, 2, 3]).unwrap();
+        storage.store("other", &[]).unwrap();
+        storage.store("state", &[4]).unwrap();
+        assert_eq!(storage.retrieve("state").unwrap(), Some(vec![4]));
+        assert_eq!(storage.retrieve("other").unwrap(), Some(vec![]));
+        assert!(storage.holds("state", &[4]) && !storage.holds("state", &[1, 2, 3]));
+        for key in ["", ".state.tmp", "../state"] {
+            match storage.store(key, &[0]) {
+                MyResult::Error(StorageError::InvalidKey) => {},
+                _ => panic!("Expected {:?} to be rejected\n", key),
+            }
+        }
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        check_store_then_retrieve(&mut MemoryStorage::new());
+    }
+
+    #[test]
+    fn test_file_storage_survives_reopen() {
+        let dir = std::env::temp_dir().join(format!("plink-storage-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        check_store_then_retrieve(&mut FileStorage::open(&dir).unwrap());
+        let storage = FileStorage::open(&dir).unwrap();
+        assert_eq!(storage.retrieve("state").unwrap(), Some(vec![4]));
+        // Only the keys are left, no temporary file
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
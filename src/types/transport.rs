@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+
+use self::messaging::Message;
+
+use super::*;
+use super::socket::SocketError;
+
+type MyResult<T> = crate::types::MyResult<T, SocketError>;
+
+/// A connection that carries whole Message frames between two endpoints.
+/// The sender and receiver state machines only talk to the network through this trait,
+/// Socket is the TCP implementation
+pub trait Transport: Sized {
+    fn connect(dest: String) -> MyResult<Self>;
+
+    #[ensures(result.is_ok() ==> self.nsent() == old(self.nsent()) + 1)]
+    #[ensures(self.nrecv() == old(self.nrecv()))]
+    #[ensures(!result.is_ok() ==> self.nsent() == old(self.nsent()))]
+    fn send_frame(&mut self, msg: Message) -> MyResult<usize>;
+
+    /// Block until a frame arrives or the read timeout expires with SocketError::Timeout
+    #[ensures(result.is_ok() ==> self.nrecv() == old(self.nrecv()) + 1)]
+    #[ensures(!result.is_ok() ==> self.nrecv() == old(self.nrecv()))]
+    fn recv_frame(&mut self) -> MyResult<Message>;
+
+    /// Take a frame that already arrived without waiting, SocketError::Timeout if there is none
+    #[ensures(result.is_ok() ==> self.nrecv() == old(self.nrecv()) + 1)]
+    #[ensures(!result.is_ok() ==> self.nrecv() == old(self.nrecv()))]
+    fn try_recv_frame(&mut self) -> MyResult<Message>;
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> MyResult<()>;
+
+    /// Number of frames sent so far
+    #[pure]
+    fn nsent(&self) -> usize;
+
+    /// Number of frames received so far
+    #[pure]
+    fn nrecv(&self) -> usize;
+}
+
+/// Next frame before deadline, None once it has passed
+pub fn recv_until<T: Transport>(socket: &mut T, deadline: Instant) -> MyResult<Option<Message>> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return MyResult::Value(None);
+    }
+    if socket.set_read_timeout(remaining).is_err() {
+        return MyResult::Error(SocketError::SetTimeoutFailed);
+    }
+    match socket.recv_frame() {
+        MyResult::Value(msg) => MyResult::Value(Some(msg)),
+        MyResult::Error(SocketError::Timeout) => MyResult::Value(None),
+        MyResult::Error(e) => MyResult::Error(e),
+    }
+}
+
+/// Accepts incoming Transport connections, ServerSocket is the TCP implementation
+pub trait Listener: Sized {
+    type Transport: Transport;
+
+    fn bind(src: String) -> MyResult<Self>;
+
+    fn accept(&self) -> MyResult<Self::Transport>;
+}
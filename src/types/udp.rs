@@ -0,0 +1,310 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use self::messaging::Message;
+
+use super::*;
+use super::array::Array;
+use super::socket::SocketError::{self, *};
+use super::transport::{Listener, Transport};
+
+type MyResult<T> = crate::types::MyResult<T, SocketError>;
+
+/// Largest payload of a single UDP datagram
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Silence after which an accepted peer is taken to be gone, UDP never reports it
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Transport that sends every Message as one UDP datagram.
+/// Datagrams may be lost, duplicated or reordered, so this is a genuine fair-loss link
+/// and reliability comes from the sender and receiver state machines alone
+pub struct DatagramSocket {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    // Deadline of a whole recv_frame call, datagrams from other peers do not extend it
+    read_timeout: Option<Duration>,
+    // Accepted sockets end the session after this long without a datagram from the peer
+    idle_timeout: Option<Duration>,
+    last_heard: Instant,
+    sent: Array<u64>,
+    received: Array<u64>,
+}
+
+/// Bound UDP port serving one peer at a time.
+/// A peer is accepted when its first datagram arrives, datagrams from other peers are
+/// dropped while it is served, which is indistinguishable from loss. A peer that stays
+/// silent for the idle timeout is gone: recv_frame reports ConnectionClosed, so the
+/// next peer can be accepted
+pub struct DatagramServerSocket {
+    socket: UdpSocket,
+    idle_timeout: Duration,
+}
+
+impl DatagramSocket {
+    fn new(socket: UdpSocket, peer: SocketAddr) -> Self {
+        DatagramSocket { socket, peer, read_timeout: None, idle_timeout: None, last_heard: Instant::now(), sent: Array::new(), received: Array::new() }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// The frame a received datagram holds, None if it is to be discarded
+    fn take(&mut self, from: SocketAddr, datagram: &[u8]) -> Option<MyResult<Message>> {
+        if from != self.peer {
+            return None;
+        }
+        self.last_heard = Instant::now();
+        let msg = Message::unmarshall(datagram)?;
+        if self.received.push(msg.id()).is_ok() {
+            Some(MyResult::Value(msg))
+        } else {
+            Some(MyResult::Error(BufferFull))
+        }
+    }
+
+    fn read_error(e: io::Error) -> SocketError {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Timeout,
+            io::ErrorKind::ConnectionRefused => DestinationUnreachable,
+            _ => RecvError,
+        }
+    }
+}
+
+impl Transport for DatagramSocket {
+    fn connect(dest: String) -> MyResult<Self> {
+        let peer = match dest.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+            Some(peer) => peer,
+            None => return MyResult::Error(DestinationUnreachable),
+        };
+        let local = if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        match UdpSocket::bind(local) {
+            Ok(socket) => MyResult::Value(DatagramSocket::new(socket, peer)),
+            Err(_) => MyResult::Error(BindError),
+        }
+    }
+
+    fn send_frame(&mut self, msg: Message) -> MyResult<usize> {
+        let id = msg.id();
+        let buf = msg.marshall();
+        if buf.len() > MAX_DATAGRAM_SIZE {
+            return MyResult::Error(FrameTooLarge);
+        }
+        match self.socket.send_to(&buf, self.peer) {
+            Ok(n) => {
+                if self.sent.push(id).is_ok() {
+                    MyResult::Value(n)
+                } else {
+                    MyResult::Error(BufferFull)
+                }
+            },
+            Err(_) => MyResult::Error(SendError),
+        }
+    }
+
+    /// Datagrams from other peers and datagrams that do not hold a valid frame are
+    /// discarded, the read timeout covers the whole call
+    fn recv_frame(&mut self) -> MyResult<Message> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let t0 = Instant::now();
+        loop {
+            let mut wait = None;
+            if let Some(timeout) = self.read_timeout {
+                let remaining = timeout.saturating_sub(t0.elapsed());
+                if remaining.is_zero() {
+                    return MyResult::Error(Timeout);
+                }
+                wait = Some(remaining);
+            }
+            if let Some(idle) = self.idle_timeout {
+                let remaining = idle.saturating_sub(self.last_heard.elapsed());
+                if remaining.is_zero() {
+                    return MyResult::Error(ConnectionClosed);
+                }
+                wait = Some(wait.map_or(remaining, |w: Duration| w.min(remaining)));
+            }
+            if self.socket.set_read_timeout(wait).is_err() {
+                return MyResult::Error(SetTimeoutFailed);
+            }
+            match self.socket.recv_from(&mut buf) {
+                Ok((n, from)) => {
+                    if let Some(res) = self.take(from, &buf[..n]) {
+                        return res;
+                    }
+                },
+                // Whichever deadline ran out is reported at the top of the loop
+                Err(e) => match DatagramSocket::read_error(e) {
+                    Timeout => {},
+                    e => return MyResult::Error(e),
+                },
+            }
+        }
+    }
+
+    fn try_recv_frame(&mut self) -> MyResult<Message> {
+        if self.socket.set_nonblocking(true).is_err() {
+            return MyResult::Error(SetTimeoutFailed);
+        }
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let res = loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((n, from)) => {
+                    if let Some(res) = self.take(from, &buf[..n]) {
+                        break res;
+                    }
+                },
+                Err(e) => break MyResult::Error(DatagramSocket::read_error(e)),
+            }
+        };
+        // The socket is shared with the listener, which blocks in accept
+        if self.socket.set_nonblocking(false).is_err() {
+            return MyResult::Error(SetTimeoutFailed);
+        }
+        res
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> MyResult<()> {
+        if timeout.is_zero() {
+            return MyResult::Error(SetTimeoutFailed);
+        }
+        self.read_timeout = Some(timeout);
+        MyResult::Value(())
+    }
+
+    #[pure]
+    fn nsent(&self) -> usize {
+        self.sent.len()
+    }
+
+    #[pure]
+    fn nrecv(&self) -> usize {
+        self.received.len()
+    }
+}
+
+impl DatagramServerSocket {
+    pub fn local_addr(&self) -> MyResult<String> {
+        match self.socket.local_addr() {
+            Ok(addr) => MyResult::Value(addr.to_string()),
+            Err(_) => MyResult::Error(BindError),
+        }
+    }
+
+    /// Silence after which accepted peers are gone, IDLE_TIMEOUT by default
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+}
+
+impl Listener for DatagramServerSocket {
+    type Transport = DatagramSocket;
+
+    fn bind(src: String) -> MyResult<Self> {
+        match UdpSocket::bind(src) {
+            Ok(socket) => MyResult::Value(DatagramServerSocket { socket, idle_timeout: IDLE_TIMEOUT }),
+            Err(_) => MyResult::Error(BindError),
+        }
+    }
+
+    /// Wait for a datagram and serve its sender, the datagram is left for the first recv_frame
+    fn accept(&self) -> MyResult<DatagramSocket> {
+        if self.socket.set_read_timeout(None).is_err() {
+            return MyResult::Error(SetTimeoutFailed);
+        }
+        let mut buf = [0; 1];
+        let peer = loop {
+            match self.socket.peek_from(&mut buf) {
+                Ok((_, peer)) => break peer,
+                // Windows reports ICMP errors of earlier sends here
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => {},
+                Err(_) => return MyResult::Error(AcceptError),
+            }
+        };
+        match self.socket.try_clone() {
+            Ok(socket) => {
+                let mut socket = DatagramSocket::new(socket, peer);
+                socket.idle_timeout = Some(self.idle_timeout);
+                MyResult::Value(socket)
+            },
+            Err(_) => MyResult::Error(AcceptError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use crate::messaging::Message;
+    use crate::receiver::PerfectLinkReceiver;
+    use crate::sender::{PerfectLinkSender, RetryPolicy};
+    use crate::types::socket::SocketError;
+    use crate::types::transport::{Listener, Transport};
+    use crate::types::MyResult;
+
+    use super::{DatagramServerSocket, DatagramSocket};
+
+    #[test]
+    pub fn test_datagram_per_frame() {
+        let server = DatagramServerSocket::bind("127.0.0.1:0".to_string()).unwrap();
+        let mut client = DatagramSocket::connect(server.local_addr().unwrap()).unwrap();
+        let msg = Message::Data { id: 3, data: vec![5; 60_000] };
+        client.send_frame(msg.clone()).unwrap();
+        let mut s = server.accept().unwrap();
+        assert_eq!(s.recv_frame().unwrap(), msg);
+        s.send_frame(Message::Ack { id: 3, window: 1 }).unwrap();
+        assert_eq!(client.recv_frame().unwrap(), Message::Ack { id: 3, window: 1 });
+        client.set_read_timeout(Duration::from_millis(10)).unwrap();
+        match client.recv_frame() {
+            MyResult::Error(SocketError::Timeout) => {},
+            _ => panic!("Expected timeout"),
+        }
+        assert!(client.send_frame(Message::Data { id: 4, data: vec![0; 70_000] }).is_err());
+    }
+
+    #[test]
+    pub fn test_silent_peer_ends_session() {
+        let server = DatagramServerSocket::bind("127.0.0.1:0".to_string()).unwrap()
+            .with_idle_timeout(Duration::from_millis(50));
+        let mut first = DatagramSocket::connect(server.local_addr().unwrap()).unwrap();
+        let mut second = DatagramSocket::connect(server.local_addr().unwrap()).unwrap();
+        first.send_frame(Message::Probe { id: 1 }).unwrap();
+        let mut s = server.accept().unwrap();
+        assert_eq!(s.recv_frame().unwrap(), Message::Probe { id: 1 });
+        // A read timeout shorter than the idle timeout still ends in Timeout
+        s.set_read_timeout(Duration::from_millis(10)).unwrap();
+        match s.recv_frame() {
+            MyResult::Error(SocketError::Timeout) => {},
+            _ => panic!("Expected timeout"),
+        }
+        s.set_read_timeout(Duration::from_secs(5)).unwrap();
+        match s.recv_frame() {
+            MyResult::Error(SocketError::ConnectionClosed) => {},
+            _ => panic!("Expected the session to end"),
+        }
+        second.send_frame(Message::Probe { id: 2 }).unwrap();
+        let mut s = server.accept().unwrap();
+        assert_eq!(s.recv_frame().unwrap(), Message::Probe { id: 2 });
+    }
+
+    #[test]
+    pub fn test_perfect_link_over_udp() {
+        let listener = DatagramServerSocket::bind("127.0.0.1:0".to_string()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let mut receiver = PerfectLinkReceiver::listen(listener);
+        thread::spawn(move || {
+            receiver.run(|_, data| tx.send(data.to_vec()).unwrap(), |_| {});
+        });
+        let mut sender: PerfectLinkSender<DatagramSocket> = PerfectLinkSender::new(addr, RetryPolicy::forever(Duration::from_millis(50)));
+        for i in 0..10u8 {
+            sender.send(vec![i; 1000]).unwrap();
+        }
+        for i in 0..10u8 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec![i; 1000]);
+        }
+    }
+}